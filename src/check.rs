@@ -0,0 +1,409 @@
+//! Finds mistakes in layouts that would otherwise only show up while typing, so configurations
+//! can be checked in CI
+
+use std::collections::{BTreeSet, HashSet};
+
+use serde::Serialize;
+
+use crate::{
+    config::{Config, KeyAction, LayoutConfig},
+    engine::{self, SPECIAL_KEYS, SPLIT_SEPARATOR},
+};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The keyboard crashes or a key can't be used
+    Error,
+    /// Probably a mistake, but the keyboard works
+    Warning,
+}
+
+/// Something wrong with the configuration
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub severity: Severity,
+    /// What kind of problem this is, e.g. "dangling-page", for tools to filter on
+    pub kind: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub layout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page: Option<String>,
+    /// The row's index in the page, starting from 0
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub row: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+impl Problem {
+    fn new(severity: Severity, kind: &'static str, message: String) -> Self {
+        Problem {
+            severity,
+            kind,
+            message,
+            layout: None,
+            page: None,
+            row: None,
+            key: None,
+        }
+    }
+
+    fn error(kind: &'static str, message: String) -> Self {
+        Self::new(Severity::Error, kind, message)
+    }
+
+    fn warning(kind: &'static str, message: String) -> Self {
+        Self::new(Severity::Warning, kind, message)
+    }
+
+    fn layout(mut self, layout: &str) -> Self {
+        self.layout = Some(layout.to_string());
+        self
+    }
+
+    fn page(mut self, page: &str) -> Self {
+        self.page = Some(page.to_string());
+        self
+    }
+
+    fn row(mut self, row: usize) -> Self {
+        self.row = Some(row);
+        self
+    }
+
+    fn key(mut self, key: &str) -> Self {
+        self.key = Some(key.to_string());
+        self
+    }
+}
+
+/// Check every layout and page in `config`, returning errors before warnings
+pub fn check(config: &Config) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    if !config.layouts.contains_key(&config.layout) {
+        problems.push(Problem::error(
+            "missing-layout",
+            format!("The selected layout '{}' doesn't exist", config.layout),
+        ));
+    }
+
+    let mut layouts: Vec<_> = config.layouts.iter().collect();
+    layouts.sort_by_key(|(name, _)| *name);
+    for (name, layout) in layouts {
+        check_layout(config, name, layout, &mut problems);
+    }
+
+    let used: HashSet<&String> = config
+        .layouts
+        .values()
+        .flat_map(|layout| {
+            let overrides = [&layout.portrait, &layout.landscape];
+            layout.pages.values().chain(
+                overrides
+                    .into_iter()
+                    .flatten()
+                    .flat_map(|pages| pages.values()),
+            )
+        })
+        .collect();
+    let mut pages: Vec<_> = config.pages.iter().collect();
+    pages.sort_by_key(|(name, _)| *name);
+    for (name, page) in pages {
+        if !used.contains(name) {
+            problems.push(
+                Problem::warning(
+                    "unused-page",
+                    format!("Page '{}' isn't used by any layout", name),
+                )
+                .page(name),
+            );
+        }
+        check_page(config, name, &page.keys, &mut problems);
+    }
+
+    problems.sort_by_key(|problem| problem.severity);
+    problems
+}
+
+fn check_layout(config: &Config, name: &str, layout: &LayoutConfig, problems: &mut Vec<Problem>) {
+    if !layout.pages.contains_key("default") {
+        problems.push(
+            Problem::error(
+                "missing-default",
+                format!("Layout '{}' has no 'default' page", name),
+            )
+            .layout(name),
+        );
+    }
+
+    let orientations = [
+        ("", Some(&layout.pages)),
+        ("portrait.", layout.portrait.as_ref()),
+        ("landscape.", layout.landscape.as_ref()),
+    ];
+    for (prefix, pages) in orientations {
+        let mut pages: Vec<_> = pages.into_iter().flatten().collect();
+        pages.sort();
+        for (role, page) in pages {
+            if !config.pages.contains_key(page) {
+                problems.push(
+                    Problem::error(
+                        "dangling-page",
+                        format!(
+                            "Layout '{}' uses page '{}' for {}{}, but it doesn't exist",
+                            name, page, prefix, role
+                        ),
+                    )
+                    .layout(name)
+                    .page(page),
+                );
+            }
+        }
+    }
+
+    // Follow page switching keys from the default page to find the roles that can be reached
+    let mut reachable = BTreeSet::from(["default".to_string()]);
+    let mut queue = vec!["default".to_string()];
+    while let Some(role) = queue.pop() {
+        let pages = [&layout.portrait, &layout.landscape]
+            .into_iter()
+            .flatten()
+            .filter_map(|pages| pages.get(&role))
+            .chain(layout.pages.get(&role));
+        for page in pages.filter_map(|page| config.pages.get(page)) {
+            for key_name in page.keys.iter().flat_map(|row| row.split(' ')) {
+                let key = engine::key(config, key_name);
+                // Holding or double tapping a key can switch pages too
+                let others = [&key.hold, &key.double_tap];
+                let keys = others
+                    .into_iter()
+                    .flatten()
+                    .map(|other| engine::key(config, other))
+                    .chain([key.clone()]);
+                let targets: Vec<String> = keys
+                    .filter_map(|key| engine::page_switch(&key).map(str::to_string))
+                    .collect();
+                for target in targets {
+                    if !layout.pages.contains_key(&target) {
+                        let message = format!(
+                            "Key '{}' switches to the '{}' page, which layout '{}' doesn't have",
+                            key_name, target, name
+                        );
+                        problems.push(
+                            Problem::error("dangling-page", message)
+                                .layout(name)
+                                .key(key_name),
+                        );
+                    } else if reachable.insert(target.clone()) {
+                        queue.push(target);
+                    }
+                }
+            }
+        }
+    }
+    let mut roles: Vec<_> = layout.pages.keys().collect();
+    roles.sort();
+    for role in roles.into_iter().filter(|role| !reachable.contains(*role)) {
+        problems.push(
+            Problem::warning(
+                "unreachable-page",
+                format!(
+                    "Layout '{}' has a '{}' page, but no key switches to it",
+                    name, role
+                ),
+            )
+            .layout(name)
+            .page(&layout.pages[role]),
+        );
+    }
+}
+
+fn check_page(config: &Config, name: &str, rows: &[String], problems: &mut Vec<Problem>) {
+    let has_shift = rows
+        .iter()
+        .flat_map(|row| row.split(' '))
+        .any(|key_name| engine::key(config, key_name).char == "<shift>");
+    let widths: Vec<usize> = rows
+        .iter()
+        .map(|row| row.split(' ').filter(|key| *key != SPLIT_SEPARATOR).count())
+        .collect();
+
+    for (i, row) in rows.iter().enumerate() {
+        let mut seen = HashSet::new();
+        for key_name in row.split(' ').filter(|key| *key != SPLIT_SEPARATOR) {
+            let at = |problem: Problem| problem.page(name).row(i).key(key_name);
+            let key = engine::key(config, key_name);
+
+            if key.char.is_empty() {
+                problems.push(at(Problem::error(
+                    "empty-key",
+                    format!(
+                        "Row {} of page '{}' has an empty key, from two spaces in a row",
+                        i, name
+                    ),
+                )));
+                continue;
+            }
+            match &key.action {
+                Some(KeyAction::Layout { layout }) if !config.layouts.contains_key(layout) => {
+                    problems.push(at(Problem::error(
+                        "dangling-layout",
+                        format!(
+                            "Key '{}' on page '{}' switches to layout '{}', which doesn't exist",
+                            key_name, name, layout
+                        ),
+                    )))
+                }
+                Some(KeyAction::Command { command }) if command.is_empty() => {
+                    problems.push(at(Problem::error(
+                        "empty-command",
+                        format!("Key '{}' on page '{}' has an empty command", key_name, name),
+                    )))
+                }
+                _ => {}
+            }
+            let is_special =
+                key.char.len() > 2 && key.char.starts_with('<') && key.char.ends_with('>');
+            if key.action.is_none() && is_special && !SPECIAL_KEYS.contains(&key.char.as_str()) {
+                problems.push(at(Problem::error(
+                    "unknown-key",
+                    format!(
+                        "Key '{}' on page '{}' isn't a special key, and would type '{}'",
+                        key_name, name, key.char
+                    ),
+                )));
+            }
+            if !seen.insert(key_name) {
+                problems.push(at(Problem::warning(
+                    "duplicate-key",
+                    format!(
+                        "Key '{}' appears more than once in row {} of page '{}'",
+                        key_name, i, name
+                    ),
+                )));
+            }
+            let is_letter = key.char.chars().count() == 1
+                && key
+                    .char
+                    .chars()
+                    .all(|c| c.is_alphabetic() && c.is_lowercase());
+            if has_shift && is_letter && key.upper.is_none() {
+                problems.push(at(Problem::warning(
+                    "missing-upper",
+                    format!(
+                        "Key '{}' on page '{}' has no upper variant, so shift doesn't change it",
+                        key_name, name
+                    ),
+                )));
+            }
+        }
+
+        // Keys in every row are sized to fit the widest one, so one long row squashes the others
+        let widest_other = widths
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| *j != i)
+            .map(|(_, width)| *width)
+            .max();
+        if let Some(widest_other) = widest_other.filter(|widest| widths[i] >= widest + 2) {
+            problems.push(
+                Problem::warning(
+                    "wide-row",
+                    format!(
+                        "Row {} of page '{}' has {} keys, but no other row has more than {}",
+                        i, name, widths[i], widest_other
+                    ),
+                )
+                .page(name)
+                .row(i),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    /// The kinds of problems found in `user_config`, with the page or layout they're on
+    fn problems(user_config: &str) -> Vec<(Severity, &'static str, Option<String>)> {
+        check(&config::from_toml(user_config).unwrap())
+            .into_iter()
+            .map(|problem| {
+                let place = problem.page.or(problem.layout);
+                (problem.severity, problem.kind, place)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn built_in_config() {
+        // Dvorak has no key to switch to the symbols page
+        assert_eq!(
+            problems(""),
+            vec![(
+                Severity::Warning,
+                "unreachable-page",
+                Some("symbols".to_string())
+            )]
+        );
+    }
+
+    #[test]
+    fn dangling_pages() {
+        let problems = problems(
+            "
+            layout = 'test'
+            [layouts.test]
+            default = 'test'
+            symbols = 'missing'
+            [layouts.test.portrait]
+            default = 'also-missing'
+            [pages.test]
+            keys = ['a <symbols>']
+            ",
+        );
+        assert!(problems.contains(&(
+            Severity::Error,
+            "dangling-page",
+            Some("missing".to_string())
+        )));
+        assert!(problems.contains(&(
+            Severity::Error,
+            "dangling-page",
+            Some("also-missing".to_string())
+        )));
+    }
+
+    #[test]
+    fn page_problems() {
+        let problems = problems(
+            "
+            layout = 'test'
+            [layouts.test]
+            default = 'test'
+            [pages.test]
+            keys = ['<shift> a ä a', 'b  <oops>', 'c d e f g h']
+            ",
+        );
+        let test = Some("test".to_string());
+        let kinds: Vec<_> = problems
+            .iter()
+            .filter(|(_, _, page)| *page == test)
+            .map(|(severity, kind, _)| (*severity, *kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (Severity::Error, "empty-key"),
+                (Severity::Error, "unknown-key"),
+                (Severity::Warning, "missing-upper"),
+                (Severity::Warning, "duplicate-key"),
+                (Severity::Warning, "wide-row"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,249 @@
+//! Subcommands that work without starting the keyboard
+
+use std::path::{Path, PathBuf};
+
+use dweeb::{
+    check::{self, Problem, Severity},
+    config::{self, Orientation},
+    engine::{KeyboardEngine, Layer},
+    render::{self, Picture},
+};
+use gtk::{cairo, gdk::prelude::GdkCairoContextExt, gdk_pixbuf::Pixbuf};
+
+const RENDER_USAGE: &str = "Usage: dweeb render [--layout <name>] [--page <role>] \
+[--layer normal|shifted|locked] [--split] [--monitor <width>x<height>] [--out <file.svg|file.png>]";
+
+const CHECK_USAGE: &str = "Usage: dweeb check [--strict] [<config.toml>]";
+
+/// `dweeb check`: look for mistakes in a configuration file, the user's by default, and print
+/// them as JSON. Fails if there are errors, or any problems at all with `--strict`.
+pub fn check(args: &[String]) -> Result<(), String> {
+    let mut strict = false;
+    let mut path = None;
+    for arg in args {
+        match arg.as_str() {
+            "--strict" => strict = true,
+            option if option.starts_with('-') => {
+                return Err(format!("Unknown option '{}'\n{}", option, CHECK_USAGE))
+            }
+            _ if path.is_some() => return Err(CHECK_USAGE.to_string()),
+            _ => path = Some(PathBuf::from(arg)),
+        }
+    }
+
+    let config = match &path {
+        Some(path) => config::load(path),
+        None => config::get_config(),
+    };
+    let problems = match config {
+        Ok(config) => check::check(&config),
+        Err(e) => vec![Problem {
+            severity: Severity::Error,
+            kind: "parse",
+            message: e,
+            layout: None,
+            page: None,
+            row: None,
+            key: None,
+        }],
+    };
+
+    let ok = !problems
+        .iter()
+        .any(|problem| strict || problem.severity == Severity::Error);
+    let report = serde_json::json!({ "ok": ok, "problems": problems });
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&report).map_err(|e| e.to_string())?
+    );
+    if !ok {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// `dweeb schema`: print a JSON Schema for config.toml
+pub fn schema() -> Result<(), String> {
+    let schema = serde_json::to_string_pretty(&config::schema()).map_err(|e| e.to_string())?;
+    println!("{}", schema);
+    Ok(())
+}
+
+/// `dweeb render`: draw a page of the keyboard to an SVG or PNG file, or SVG on stdout
+pub fn render(args: &[String]) -> Result<(), String> {
+    let mut config = config::get_config()?;
+    let mut page = "default".to_string();
+    let mut layer = Layer::Normal;
+    let mut monitor_size = (1920, 1080);
+    let mut out: Option<PathBuf> = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("Missing value for '{}'\n{}", arg, RENDER_USAGE))
+        };
+        match arg.as_str() {
+            "--layout" => config.layout = value()?.clone(),
+            "--page" => page = value()?.clone(),
+            "--layer" => {
+                layer = match value()?.as_str() {
+                    "normal" => Layer::Normal,
+                    "shifted" => Layer::Shifted,
+                    "locked" => Layer::Locked,
+                    other => return Err(format!("Unknown layer '{}'", other)),
+                }
+            }
+            "--split" => config.split = true,
+            "--monitor" => {
+                let size = value()?;
+                monitor_size = size
+                    .split_once('x')
+                    .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+                    .ok_or_else(|| {
+                        format!("Invalid monitor size '{}', expected e.g. 1920x1080", size)
+                    })?;
+            }
+            "--out" => out = Some(value()?.into()),
+            other => return Err(format!("Unknown option '{}'\n{}", other, RENDER_USAGE)),
+        }
+    }
+
+    let Some(layout) = config.layouts.get(&config.layout) else {
+        return Err(format!("Layout '{}' not found", config.layout));
+    };
+    if !layout.pages.contains_key(&page) {
+        let mut roles: Vec<&str> = layout.pages.keys().map(String::as_str).collect();
+        roles.sort();
+        return Err(format!(
+            "Layout '{}' has no '{}' page, expected one of: {}",
+            config.layout,
+            page,
+            roles.join(", ")
+        ));
+    }
+
+    let mut engine = KeyboardEngine::new(config);
+    engine.set_orientation(Orientation::from_size(monitor_size.0, monitor_size.1));
+    engine.set_page(&page);
+    engine.set_layer(layer);
+    if !engine.config().pages.contains_key(engine.page_name()) {
+        return Err(format!("Page '{}' not found", engine.page_name()));
+    }
+    let picture = render::picture(&engine, monitor_size);
+
+    match out {
+        None => print!("{}", render::svg(&picture)),
+        Some(path) => match path.extension().and_then(|extension| extension.to_str()) {
+            Some("png") => write_png(&picture, &path)?,
+            Some("svg") => std::fs::write(&path, render::svg(&picture))
+                .map_err(|e| format!("Could not write {}: {}", path.to_string_lossy(), e))?,
+            _ => return Err("The output file has to end in .svg or .png".to_string()),
+        },
+    }
+    Ok(())
+}
+
+/// Draw `picture` with cairo, in the same colors as the SVG
+fn write_png(picture: &Picture, path: &Path) -> Result<(), String> {
+    let surface = cairo::ImageSurface::create(cairo::Format::ARgb32, picture.width, picture.height)
+        .map_err(|e| e.to_string())?;
+    let cr = cairo::Context::new(&surface).map_err(|e| e.to_string())?;
+    let hex = |rgb: u32| {
+        (
+            (rgb >> 16) as f64 / 255.0,
+            ((rgb >> 8) & 0xff) as f64 / 255.0,
+            (rgb & 0xff) as f64 / 255.0,
+        )
+    };
+    let set_color = |rgb: u32| {
+        let (r, g, b) = hex(rgb);
+        cr.set_source_rgb(r, g, b);
+    };
+
+    set_color(0xdeddda);
+    cr.paint().map_err(|e| e.to_string())?;
+    cr.select_font_face("Sans", cairo::FontSlant::Normal, cairo::FontWeight::Normal);
+
+    for key in &picture.keys {
+        let has_class = |class: &str| key.classes.iter().any(|c| c == class);
+        let (x, y) = (key.x as f64, key.y as f64);
+        let (width, height) = (key.width as f64, key.height as f64);
+
+        rounded_rectangle(&cr, x + 0.5, y + 0.5, width - 1.0, height - 1.0, 6.0);
+        set_color(if has_class("suggested-action") {
+            0x3584e4
+        } else if has_class("modifier-active") {
+            0xc0d7f0
+        } else if has_class("special") {
+            0xf0f0f0
+        } else {
+            0xfafafa
+        });
+        cr.fill_preserve().map_err(|e| e.to_string())?;
+        set_color(0xc0bfbc);
+        cr.set_line_width(1.0);
+        cr.stroke().map_err(|e| e.to_string())?;
+
+        let font_size = (key.height / 3).clamp(8, 32) as f64;
+        let icon_size = (font_size * 4.0 / 3.0) as i32;
+        let icon = key
+            .icon
+            .as_deref()
+            .and_then(render::find_icon)
+            .and_then(|path| Pixbuf::from_file_at_size(path, icon_size, icon_size).ok());
+        set_color(0x241f31);
+        if let Some(icon) = icon {
+            cr.set_source_pixbuf(
+                &icon,
+                x + (width - icon.width() as f64) / 2.0,
+                y + (height - icon.height() as f64) / 2.0,
+            );
+            cr.paint().map_err(|e| e.to_string())?;
+        } else {
+            let label = key.text();
+            cr.set_font_size(if key.icon.is_some() {
+                font_size / 2.0
+            } else {
+                font_size
+            });
+            let extents = cr.text_extents(label).map_err(|e| e.to_string())?;
+            cr.move_to(
+                x + (width - extents.width()) / 2.0 - extents.x_bearing(),
+                y + (height - extents.height()) / 2.0 - extents.y_bearing(),
+            );
+            cr.show_text(label).map_err(|e| e.to_string())?;
+        }
+
+        if let Some(hint) = &key.hint {
+            set_color(0x77767b);
+            cr.set_font_size(font_size / 2.0);
+            let extents = cr.text_extents(hint).map_err(|e| e.to_string())?;
+            cr.move_to(
+                x + width - 4.0 - extents.x_advance(),
+                y + 4.0 - extents.y_bearing(),
+            );
+            cr.show_text(hint).map_err(|e| e.to_string())?;
+        }
+    }
+
+    let mut file = std::fs::File::create(path)
+        .map_err(|e| format!("Could not write {}: {}", path.to_string_lossy(), e))?;
+    surface.write_to_png(&mut file).map_err(|e| e.to_string())
+}
+
+fn rounded_rectangle(cr: &cairo::Context, x: f64, y: f64, width: f64, height: f64, radius: f64) {
+    use std::f64::consts::{FRAC_PI_2, PI};
+    cr.new_sub_path();
+    cr.arc(x + width - radius, y + radius, radius, -FRAC_PI_2, 0.0);
+    cr.arc(
+        x + width - radius,
+        y + height - radius,
+        radius,
+        0.0,
+        FRAC_PI_2,
+    );
+    cr.arc(x + radius, y + height - radius, radius, FRAC_PI_2, PI);
+    cr.arc(x + radius, y + radius, radius, PI, 3.0 * FRAC_PI_2);
+    cr.close_path();
+}
@@ -1,22 +1,206 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
+use schemars::{
+    gen::{SchemaGenerator, SchemaSettings},
+    schema::{RootSchema, Schema},
+    JsonSchema,
+};
 use serde::Deserialize;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
 pub struct Config {
+    /// The layout to use, from `[layouts]`
     pub layout: String,
+    /// The space between keys, in pixels
     pub key_spacing: i32,
+    /// The space around the keys, in pixels
     pub padding: i32,
-    pub width: i32,
+    /// The keyboard's maximum width, in pixels or as a percentage of the monitor width
+    pub width: Size,
+    /// The keyboard's height, in pixels or as a percentage of the monitor height
+    pub height: Size,
+    /// The height of each key, in pixels or as a percentage of the keyboard height.
+    /// By default, keys are sized to fill the keyboard height.
+    pub key_height: Option<Size>,
+    /// How long (in milliseconds) to wait before closing the keyboard when no text field is
+    /// focused, so it doesn't flash in and out when switching between text fields
+    pub close_delay: u64,
+    /// How long (in milliseconds) a key has to be held down to do its `hold` key or hint
+    pub hold_time: u64,
+    /// How soon (in milliseconds) a key has to be tapped again to do its `double_tap` key
+    pub double_tap_time: u64,
+    /// How shift is locked into caps lock
+    pub caps_lock: CapsLock,
+    /// How soon (in milliseconds) shift has to be tapped again to lock it. Defaults to
+    /// `double_tap_time`.
+    pub caps_lock_time: Option<u64>,
+    /// How long (in milliseconds) the keyboard takes to slide in and out, or 0 to disable it
+    pub animation_duration: u32,
+    /// The screen edge the keyboard is attached to
+    pub anchor: Anchor,
+    /// The layer shell layer to show the keyboard in
+    #[serde(rename = "layer")]
+    pub shell_layer: ShellLayer,
+    /// Whether to push other windows out of the way, rather than covering them
+    pub exclusive: bool,
+    /// The output (monitor) to show the keyboard on
+    #[schemars(with = "String")]
+    pub output: OutputSelection,
+    /// The seat to act as an input method for, e.g. "seat0". By default, keys are typed on
+    /// whichever seat most recently focused a text field.
+    pub seat: Option<String>,
+    /// More files of layouts, pages and keys, relative to the config file. Files in the
+    /// `layouts` directory next to it are loaded as well.
+    pub include: Vec<PathBuf>,
+    /// Whether the keyboard floats above other windows and can be dragged around, rather than
+    /// being docked to the bottom of the screen
+    pub floating: bool,
+    /// Whether to start with the keyboard split into halves at the edges of the screen
+    pub split: bool,
+    /// The gap between the halves of a split keyboard, in pixels or as a percentage of the
+    /// monitor width
+    pub split_gap: Size,
+    /// The name of the theme to load, or "auto" to follow the system's light/dark preference
+    pub theme: String,
+    /// The theme used by "auto" when the system prefers a light color scheme
+    pub light_theme: String,
+    /// The theme used by "auto" when the system prefers a dark color scheme
+    pub dark_theme: String,
 
-    pub layouts: HashMap<String, HashMap<String, String>>,
+    /// Layouts by name, each choosing a page for every role
+    pub layouts: HashMap<String, LayoutConfig>,
+    /// Keys by the name they're written as in pages, for keys that don't just type their name
     pub keys: HashMap<String, KeyConfig>,
+    /// Pages by name
     pub pages: HashMap<String, PageConfig>,
 }
 
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Anchor {
+    Top,
+    Bottom,
+}
+
+#[derive(Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CapsLock {
+    /// Tapping shift twice locks it
+    DoubleTap,
+    /// Holding shift down locks it
+    LongPress,
+    /// Shift only ever applies to one key, unless there's a `<capslock>` key
+    Disabled,
+}
+
+#[derive(Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ShellLayer {
+    /// Above normal windows, but below fullscreen ones
+    Top,
+    /// Above everything, including fullscreen windows
+    Overlay,
+}
+
+/// Which output (monitor) the keyboard appears on
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(from = "String")]
+pub enum OutputSelection {
+    /// Follow the output with the focused text field, once the compositor has said which one it
+    /// is. Until then, the compositor chooses.
+    Focused,
+    /// Always use the output with this connector name (e.g. "DP-1")
+    Named(String),
+}
+impl From<String> for OutputSelection {
+    fn from(name: String) -> Self {
+        match name.as_str() {
+            "focused" => OutputSelection::Focused,
+            _ => OutputSelection::Named(name),
+        }
+    }
+}
+
+/// A length which is either absolute or relative to some other length
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(try_from = "SizeRepr")]
+pub enum Size {
+    Pixels(i32),
+    Percent(f64),
+}
+impl Size {
+    /// Get the length in pixels, as a portion of `total` pixels
+    pub fn resolve(self, total: i32) -> i32 {
+        match self {
+            Size::Pixels(pixels) => pixels.min(total),
+            Size::Percent(percent) => (total as f64 * percent / 100.0).round() as i32,
+        }
+    }
+}
+
+impl JsonSchema for Size {
+    fn schema_name() -> String {
+        "Size".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        SizeRepr::json_schema(gen)
+    }
+}
+
+/// Sizes may be written as a number of pixels (`300`) or a percentage (`'40%'`)
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum SizeRepr {
+    Pixels(i32),
+    Text(String),
+}
+impl TryFrom<SizeRepr> for Size {
+    type Error = String;
+
+    fn try_from(repr: SizeRepr) -> Result<Self, Self::Error> {
+        let size = match repr {
+            SizeRepr::Pixels(pixels) => Size::Pixels(pixels),
+            SizeRepr::Text(text) => {
+                let text = text.trim();
+                let parsed = match text.strip_suffix('%') {
+                    Some(percent) => percent.trim().parse().map(Size::Percent).ok(),
+                    None => text
+                        .strip_suffix("px")
+                        .unwrap_or(text)
+                        .trim()
+                        .parse()
+                        .map(Size::Pixels)
+                        .ok(),
+                };
+                parsed
+                    .ok_or_else(|| format!("invalid size '{}', expected e.g. 300 or '40%'", text))?
+            }
+        };
+        match size {
+            Size::Pixels(pixels) if pixels < 0 => Err(format!(
+                "invalid size {}px, sizes can't be negative",
+                pixels
+            )),
+            Size::Percent(percent) if !(0.0..=100.0).contains(&percent) => Err(format!(
+                "invalid size {}%, expected a percentage from 0 to 100",
+                percent
+            )),
+            size => Ok(size),
+        }
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Clone, Debug)]
+#[schemars(deny_unknown_fields)]
 pub struct KeyConfig {
-    /// The character to insert when the key is pressed (also shown on button)
+    /// The character to insert when the key is pressed (also shown on button). Defaults to the
+    /// key's name.
+    #[serde(default)]
     pub char: String,
     /// The character to insert when the key is pressed while shift is active (also shown on button)
     pub upper: Option<String>,
@@ -27,6 +211,23 @@ pub struct KeyConfig {
     /// The icon to display in place of the label.
     /// Icon names found here: https://specifications.freedesktop.org/icon-naming-spec/icon-naming-spec-latest.html
     pub icon: Option<String>,
+    /// The icon to display instead while caps lock is on
+    pub locked_icon: Option<String>,
+    /// A secondary character shown in the corner of the button, inserted on long-press or
+    /// swipe-down
+    pub hint: Option<String>,
+    /// What to do instead of inserting `char`
+    pub action: Option<KeyAction>,
+    /// The key to press instead when this one is held down, e.g. ';' or '<symbols>'. It takes
+    /// the place of the hint, which can still be typed by swiping down.
+    pub hold: Option<String>,
+    /// The key to press instead when this one is tapped twice in a row, e.g. '<capslock>'.
+    /// Whatever the first tap typed is deleted.
+    pub double_tap: Option<String>,
+    /// How long (in milliseconds) this key has to be held down, instead of `hold_time`
+    pub hold_time: Option<u64>,
+    /// How soon (in milliseconds) this key has to be tapped again, instead of `double_tap_time`
+    pub double_tap_time: Option<u64>,
 }
 impl KeyConfig {
     pub fn new(c: &str) -> Self {
@@ -35,34 +236,575 @@ impl KeyConfig {
             upper: None,
             classes: None,
             icon: None,
+            locked_icon: None,
             label: None,
+            hint: None,
+            action: None,
+            hold: None,
+            double_tap: None,
+            hold_time: None,
+            double_tap_time: None,
         }
     }
 }
 
-#[derive(Deserialize)]
+/// Something a key does other than typing a character, e.g.
+/// `action = { type = 'keys', keys = ['ctrl+c'] }`
+#[derive(Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum KeyAction {
+    /// Type some text, e.g. ".com"
+    Text { text: String },
+    /// Press keys one after another, e.g. ['Home', 'shift+End']
+    Keys { keys: Vec<KeyCombo> },
+    /// Show the page with this role in the current layout, e.g. "symbols"
+    Page { page: String },
+    /// Switch to another layout
+    Layout { layout: String },
+    /// Run a program with arguments, e.g. ['notify-send', 'Hello']
+    Command { command: Vec<String> },
+}
+
+/// A key pressed while holding modifiers, written like "ctrl+shift+t". Keys are named by their
+/// XKB keysym, e.g. "a", "Return" or "F5".
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(try_from = "String")]
+pub struct KeyCombo {
+    pub modifiers: Vec<Modifier>,
+    pub key: String,
+}
+impl TryFrom<String> for KeyCombo {
+    type Error = String;
+
+    fn try_from(combo: String) -> Result<Self, Self::Error> {
+        let mut parts: Vec<&str> = combo.split('+').map(str::trim).collect();
+        let key = parts.pop().unwrap_or_default();
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!(
+                "invalid key '{}', expected a keysym name like 'ctrl+c' or 'Return'",
+                combo
+            ));
+        }
+        let modifiers = parts
+            .into_iter()
+            .map(|modifier| match modifier.to_lowercase().as_str() {
+                "shift" => Ok(Modifier::Shift),
+                "ctrl" | "control" => Ok(Modifier::Ctrl),
+                "alt" => Ok(Modifier::Alt),
+                "super" | "logo" => Ok(Modifier::Super),
+                _ => Err(format!(
+                    "unknown modifier '{}' in '{}', expected shift, ctrl, alt or super",
+                    modifier, combo
+                )),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(KeyCombo {
+            modifiers,
+            key: key.to_string(),
+        })
+    }
+}
+impl JsonSchema for KeyCombo {
+    fn schema_name() -> String {
+        "KeyCombo".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        String::json_schema(gen)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Modifier {
+    Shift,
+    Ctrl,
+    Alt,
+    Super,
+}
+
+/// Maps page roles (like "default" or "symbols") to page names
+#[derive(Deserialize, JsonSchema)]
+pub struct LayoutConfig {
+    #[serde(flatten)]
+    pub pages: HashMap<String, String>,
+    /// Pages to use instead when the monitor is taller than it is wide
+    pub portrait: Option<HashMap<String, String>>,
+    /// Pages to use instead when the monitor is wider than it is tall
+    pub landscape: Option<HashMap<String, String>>,
+}
+impl LayoutConfig {
+    /// The name of the page to show for `role` in the given orientation, if the layout has one
+    pub fn page(&self, role: &str, orientation: Orientation) -> Option<&String> {
+        let overrides = match orientation {
+            Orientation::Portrait => &self.portrait,
+            Orientation::Landscape => &self.landscape,
+        };
+        overrides
+            .as_ref()
+            .and_then(|pages| pages.get(role))
+            .or_else(|| self.pages.get(role))
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Orientation {
+    Portrait,
+    Landscape,
+}
+impl Orientation {
+    pub fn from_size(width: i32, height: i32) -> Self {
+        if height > width {
+            Orientation::Portrait
+        } else {
+            Orientation::Landscape
+        }
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
 pub struct PageConfig {
+    /// Rows of key names separated by spaces, e.g. 'q w e r t y'
     pub keys: Vec<String>,
 }
 
-pub fn get_config() -> Result<Config, String> {
-    // Load required base config
-    let mut base_conf = include_str!("../config.toml");
+/// Where the user's configuration is read from, if there's a config directory
+pub fn user_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|path| path.join("dweeb/config.toml"))
+}
 
+pub fn get_config() -> Result<Config, String> {
+    let Some(path) = user_config_path() else {
+        eprintln!("Could not open user configuration directory.");
+        return from_toml("");
+    };
     // Try to load optional user config
-    let user_conf = match dirs::config_dir() {
-        Some(path) => std::fs::read_to_string(path.join("dweeb/config.toml")).or_else(|e| {
-            Err(format!(
-                "User configuration not found at {}",
-                path.to_string_lossy()
-            ))
-        }),
-        None => Err("Could not open user configuration directory.".to_string()),
-    }
-    .unwrap_or_else(|e| {
-        eprintln!("{}", e);
+    let user_conf = std::fs::read_to_string(&path).unwrap_or_else(|_| {
+        eprintln!("User configuration not found at {}", path.to_string_lossy());
         "".into()
     });
-    toml::from_str::<Config>(&base_conf)
+    from_files(&user_conf, path.parent())
+}
+
+/// Load the configuration file at `path` and the files it includes, on top of the built-in one
+pub fn load(path: &Path) -> Result<Config, String> {
+    let user_conf = std::fs::read_to_string(path)
+        .map_err(|e| format!("Could not read {}: {}", path.to_string_lossy(), e))?;
+    from_files(&user_conf, path.parent())
+}
+
+/// Load the configuration given by `user_conf`, on top of the built-in one
+pub fn from_toml(user_conf: &str) -> Result<Config, String> {
+    from_files(user_conf, None)
+}
+
+/// Load `user_conf` and the files it includes, where `dir` is the directory it's in. Layout
+/// files in `dir` are loaded too.
+fn from_files(user_conf: &str, dir: Option<&Path>) -> Result<Config, String> {
+    // Load required base config
+    let mut conf = parse_toml(include_str!("../config.toml"))?;
+    let user_conf = parse_toml(user_conf)?;
+    merge(&mut conf, load_includes(&user_conf, dir)?);
+    merge(&mut conf, user_conf);
+
+    conf.try_into()
         .map_err(|e| format!("Failed to parse dweeb configuration:\n\n{}", e))
 }
+
+/// Merge the files from `include` and the layouts directory, which may only add to each other
+fn load_includes(user_conf: &toml::Table, dir: Option<&Path>) -> Result<toml::Table, String> {
+    let base = dir.unwrap_or(Path::new(""));
+    let mut files = match user_conf.get("include") {
+        None => Vec::new(),
+        Some(toml::Value::Array(paths)) => paths
+            .iter()
+            .map(|path| match path {
+                toml::Value::String(path) => Ok(base.join(path)),
+                _ => Err("'include' has to be a list of file paths".to_string()),
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        Some(_) => return Err("'include' has to be a list of file paths".to_string()),
+    };
+    if let Some(Ok(entries)) = dir.map(|dir| std::fs::read_dir(dir.join("layouts"))) {
+        let mut layouts: Vec<PathBuf> = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "toml")
+            })
+            .collect();
+        layouts.sort();
+        for path in layouts {
+            if !files.contains(&path) {
+                files.push(path);
+            }
+        }
+    }
+
+    let mut included = toml::Table::new();
+    // The file each layout, page and key property came from
+    let mut origins = HashMap::new();
+    for path in files {
+        add_layout_file(&mut included, &mut origins, &path)?;
+    }
+    Ok(included)
+}
+
+/// Add the layouts, pages and keys in the file at `path` to `included`. Pages are renamed to
+/// "<file name>/<page>", so files can't break each other's layouts by using the same page names.
+fn add_layout_file(
+    included: &mut toml::Table,
+    origins: &mut HashMap<String, String>,
+    path: &Path,
+) -> Result<(), String> {
+    let file = path.to_string_lossy().to_string();
+    let source =
+        std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", file, e))?;
+    let mut table: toml::Table =
+        toml::from_str(&source).map_err(|e| format!("Failed to parse {}:\n\n{}", file, e))?;
+    if let Some(key) = table
+        .keys()
+        .find(|key| !["layouts", "pages", "keys"].contains(&key.as_str()))
+    {
+        return Err(format!(
+            "{} can only have layouts, pages and keys, not '{}'",
+            file, key
+        ));
+    }
+
+    let namespace = path.file_stem().unwrap_or_default().to_string_lossy();
+    let pages = take_table(&mut table, "pages", &file)?;
+    let rename = |page: &mut String| {
+        if pages.contains_key(page.as_str()) {
+            *page = format!("{}/{}", namespace, page);
+        }
+    };
+
+    for (name, mut layout) in take_table(&mut table, "layouts", &file)? {
+        if let toml::Value::Table(roles) = &mut layout {
+            for (_, value) in roles.iter_mut() {
+                match value {
+                    toml::Value::String(page) => rename(page),
+                    // Portrait and landscape pages
+                    toml::Value::Table(overrides) => {
+                        for (_, value) in overrides.iter_mut() {
+                            if let toml::Value::String(page) = value {
+                                rename(page);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        add_new(included, origins, "layouts", "Layout", name, layout, &file)?;
+    }
+    for (name, page) in pages {
+        let name = format!("{}/{}", namespace, name);
+        add_new(included, origins, "pages", "Page", name, page, &file)?;
+    }
+
+    // Files can add to the same key, as long as they don't disagree
+    let keys = table_mut(included, "keys");
+    for (name, key) in take_table(&mut table, "keys", &file)? {
+        let toml::Value::Table(properties) = key else {
+            return Err(format!("Key '{}' in {} has to be a table", name, file));
+        };
+        let existing = table_mut(keys, &name);
+        for (property, value) in properties {
+            let origin = format!("keys.{}.{}", name, property);
+            match existing.get(&property) {
+                Some(other) if *other != value => {
+                    return Err(format!(
+                        "Key '{}' has a different {} in {} and {}",
+                        name, property, origins[&origin], file
+                    ))
+                }
+                _ => {
+                    existing.insert(property, value);
+                    origins.insert(origin, file.clone());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Add `value` to the `section` table, unless another file already has something by that name
+fn add_new(
+    included: &mut toml::Table,
+    origins: &mut HashMap<String, String>,
+    section: &str,
+    kind: &str,
+    name: String,
+    value: toml::Value,
+    file: &str,
+) -> Result<(), String> {
+    let origin = format!("{}.{}", section, name);
+    if let Some(other) = origins.get(&origin) {
+        return Err(format!(
+            "{} '{}' is defined in both {} and {}",
+            kind, name, other, file
+        ));
+    }
+    origins.insert(origin, file.to_string());
+    table_mut(included, section).insert(name, value);
+    Ok(())
+}
+
+/// Remove the table called `name` from `table`
+fn take_table(table: &mut toml::Table, name: &str, file: &str) -> Result<toml::Table, String> {
+    match table.remove(name) {
+        None => Ok(toml::Table::new()),
+        Some(toml::Value::Table(table)) => Ok(table),
+        Some(_) => Err(format!("'{}' in {} has to be a table", name, file)),
+    }
+}
+
+/// The table called `name` in `table`, which is added if it's missing
+fn table_mut<'a>(table: &'a mut toml::Table, name: &str) -> &'a mut toml::Table {
+    let value = table
+        .entry(name)
+        .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+    if !value.is_table() {
+        *value = toml::Value::Table(toml::Table::new());
+    }
+    value.as_table_mut().unwrap()
+}
+
+/// A JSON Schema for the user's config.toml, for editors to complete and validate it with
+pub fn schema() -> RootSchema {
+    let mut settings = SchemaSettings::draft07();
+    // TOML has no null
+    settings.option_add_null_type = false;
+    let mut generator = SchemaGenerator::new(settings);
+    let mut schema = generator.root_schema_for::<Config>();
+    // Schemars loses the value type of maps flattened into a struct, like a layout's roles
+    if let Some(Schema::Object(layout)) = schema.definitions.get_mut("LayoutConfig") {
+        layout.object().additional_properties = Some(Box::new(String::json_schema(&mut generator)));
+    }
+    // The user's configuration is merged over the built-in one, so none of its settings have to
+    // be given
+    schema.schema.object().required.clear();
+    schema
+}
+
+fn parse_toml(source: &str) -> Result<toml::Table, String> {
+    toml::from_str(source).map_err(|e| format!("Failed to parse dweeb configuration:\n\n{}", e))
+}
+
+/// Recursively merge `overrides` into `base`, so user config only needs to specify what it
+/// changes.
+fn merge(base: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base_table)), toml::Value::Table(table)) => {
+                merge(base_table, table)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory of config files, deleted after the test
+    struct ConfigDir(PathBuf);
+    impl ConfigDir {
+        fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("dweeb-test-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            for (path, contents) in files {
+                let path = dir.join(path);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, contents).unwrap();
+            }
+            ConfigDir(dir)
+        }
+
+        fn load(&self) -> Result<Config, String> {
+            load(&self.0.join("config.toml"))
+        }
+    }
+    impl Drop for ConfigDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    const GERMAN: &str = "
+        [layouts.de]
+        default = 'qwertz'
+        symbols = 'symbols'
+        [pages.qwertz]
+        keys = ['q w e r t z ä']
+        [keys]
+        'ä' = { char = 'ä', upper = 'Ä' }
+    ";
+
+    #[test]
+    fn user_config_overrides_built_in() {
+        let config = from_toml("padding = 2\n[keys.a]\nupper = 'Å'").unwrap();
+        assert_eq!(config.padding, 2);
+        // Tables are merged, so only the changed values have to be given
+        assert_eq!(config.keys["a"].upper.as_deref(), Some("Å"));
+        assert_eq!(config.keys["a"].char, "a");
+        assert_eq!(config.layout, "us");
+        assert!(from_toml("padding = ").is_err());
+    }
+
+    #[test]
+    fn schema_definitions() {
+        let schema = serde_json::to_value(schema()).unwrap();
+        // Settings can be left out, since they're merged over the built-in config
+        assert_eq!(schema.get("required"), None);
+        let definitions = &schema["definitions"];
+        for name in [
+            "KeyConfig",
+            "KeyAction",
+            "LayoutConfig",
+            "PageConfig",
+            "Size",
+        ] {
+            assert!(
+                definitions.get(name).is_some(),
+                "No definition for {}",
+                name
+            );
+        }
+        // But actions still need their type and fields
+        let text = definitions["KeyAction"]["oneOf"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|variant| variant["properties"]["type"]["enum"][0] == "text")
+            .unwrap();
+        assert_eq!(text["required"], serde_json::json!(["text", "type"]));
+    }
+
+    #[test]
+    fn sizes() {
+        let config = from_toml("width = '80%'\nheight = '200px'").unwrap();
+        assert_eq!(config.width.resolve(1000), 800);
+        assert_eq!(config.height.resolve(1000), 200);
+        for size in ["'-20%'", "'120%'", "-5", "'-5px'", "'wide'"] {
+            let error = from_toml(&format!("height = {}", size)).err();
+            assert!(
+                error.is_some_and(|e| e.contains("invalid size")),
+                "{}",
+                size
+            );
+        }
+    }
+
+    #[test]
+    fn layouts_directory() {
+        let dir = ConfigDir::new(
+            "layouts",
+            &[
+                ("config.toml", "layout = 'de'"),
+                ("layouts/german.toml", GERMAN),
+            ],
+        );
+        let config = dir.load().unwrap();
+        let layout = &config.layouts["de"];
+        // Pages in the file are namespaced, others are shared
+        assert_eq!(layout.pages["default"], "german/qwertz");
+        assert_eq!(layout.pages["symbols"], "symbols");
+        assert_eq!(config.pages["german/qwertz"].keys, vec!["q w e r t z ä"]);
+        assert_eq!(config.keys["ä"].upper.as_deref(), Some("Ä"));
+        // Built-in layouts are still there
+        assert!(config.layouts.contains_key("us"));
+    }
+
+    #[test]
+    fn includes() {
+        let dir = ConfigDir::new(
+            "includes",
+            &[
+                (
+                    "config.toml",
+                    "include = ['more/german.toml']\n[keys]\n'ä'.upper = 'X'",
+                ),
+                ("more/german.toml", GERMAN),
+            ],
+        );
+        let config = dir.load().unwrap();
+        assert_eq!(config.layouts["de"].pages["default"], "german/qwertz");
+        // The user's own config wins
+        assert_eq!(config.keys["ä"].upper.as_deref(), Some("X"));
+    }
+
+    #[test]
+    fn key_combos() {
+        let config =
+            from_toml("[keys]\nx.action = { type = 'keys', keys = ['Ctrl + Shift+t'] }").unwrap();
+        assert_eq!(
+            config.keys["x"].action,
+            Some(KeyAction::Keys {
+                keys: vec![KeyCombo {
+                    modifiers: vec![Modifier::Ctrl, Modifier::Shift],
+                    key: "t".to_string()
+                }]
+            })
+        );
+        let error = from_toml("[keys]\nx.action = { type = 'keys', keys = ['hyper+x'] }")
+            .err()
+            .unwrap();
+        assert!(error.contains("unknown modifier 'hyper'"), "{}", error);
+    }
+
+    #[test]
+    fn conflicts() {
+        let dir = ConfigDir::new(
+            "conflicting-layouts",
+            &[
+                ("config.toml", ""),
+                ("layouts/german.toml", GERMAN),
+                ("layouts/swiss.toml", "[layouts.de]\ndefault = 'qwertz'"),
+            ],
+        );
+        let error = dir.load().err().unwrap();
+        assert!(
+            error.starts_with("Layout 'de' is defined in both"),
+            "{}",
+            error
+        );
+        assert!(error.ends_with("swiss.toml"), "{}", error);
+
+        let dir = ConfigDir::new(
+            "conflicting-keys",
+            &[
+                ("config.toml", ""),
+                ("layouts/german.toml", GERMAN),
+                (
+                    "layouts/other.toml",
+                    "[keys]\n'ä'.upper = 'A'\n'ä'.hint = '1'",
+                ),
+            ],
+        );
+        let error = dir.load().err().unwrap();
+        assert!(
+            error.starts_with("Key 'ä' has a different upper in"),
+            "{}",
+            error
+        );
+
+        let dir = ConfigDir::new(
+            "not-a-layout",
+            &[("config.toml", ""), ("layouts/german.toml", "width = 10")],
+        );
+        let error = dir.load().err().unwrap();
+        assert!(
+            error.ends_with("can only have layouts, pages and keys, not 'width'"),
+            "{}",
+            error
+        );
+    }
+}
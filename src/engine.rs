@@ -0,0 +1,1118 @@
+//! The keyboard's state and behavior, independent of how it's drawn
+
+use std::time::{Duration, Instant};
+
+use crate::{
+    config::{CapsLock, Config, KeyAction, KeyConfig, Orientation, PageConfig},
+    wayland::{ContentType, InputAction, TextField},
+};
+
+/// Shown when the layout is missing a page, rather than crashing
+static NO_PAGE: PageConfig = PageConfig { keys: Vec::new() };
+
+/// Marks where a row is divided when the keyboard is split
+pub const SPLIT_SEPARATOR: &str = "<gap>";
+
+/// The keys with built-in behavior, rather than inserting their own text
+pub const SPECIAL_KEYS: &[&str] = &[
+    "<shift>",
+    "<capslock>",
+    "<symbols>",
+    "<default>",
+    "<hide>",
+    "<split>",
+    "<gap>",
+    "<bksp>",
+];
+
+/// Represents the keyboard's shift/capslock state
+#[derive(PartialEq, Debug, Clone)]
+pub enum Layer {
+    Normal,
+    Shifted,
+    Locked,
+}
+impl Layer {
+    /// The CSS class applied to keys, rows and the window while this layer is active
+    pub fn css_class(&self) -> Option<&'static str> {
+        match self {
+            Layer::Normal => None,
+            Layer::Shifted => Some("shifted"),
+            Layer::Locked => Some("locked"),
+        }
+    }
+}
+
+/// What the frontend has to do after a key press
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Type into the focused text field
+    Input(InputAction),
+    /// The page or the split changed, so the keys have to be rebuilt
+    Render,
+    /// The shift state changed, so keys have to show their other characters
+    SetLayer(Layer),
+    /// Hide the keyboard until a different text field is focused
+    Dismiss,
+    /// Run a program, given as its arguments
+    Spawn(Vec<String>),
+}
+
+/// What a downward swipe does
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Swipe {
+    /// Type the hint of the key it started on
+    Hint,
+    /// Hide the keyboard
+    Dismiss,
+}
+
+/// How far (in pixels) a touch has to travel downwards to count as a swipe
+const HINT_SWIPE_DISTANCE: f64 = 20.0;
+
+/// How far (in pixels) a touch has to travel downwards to dismiss the keyboard.
+/// Shorter swipes on a key trigger its hint instead.
+const DISMISS_SWIPE_DISTANCE: f64 = 100.0;
+
+/// What a drag by (x, y) pixels amounts to, if it's a swipe down
+pub fn swipe(x: f64, y: f64) -> Option<Swipe> {
+    if y > DISMISS_SWIPE_DISTANCE && y > 2.0 * x.abs() {
+        Some(Swipe::Dismiss)
+    } else if y > HINT_SWIPE_DISTANCE && y > x.abs() {
+        Some(Swipe::Hint)
+    } else {
+        None
+    }
+}
+
+/// The size of a key's button, in pixels
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeySize {
+    pub width: i32,
+    pub height: i32,
+}
+
+/// The keys of a row, divided into the halves of a split keyboard.
+/// Unless the keyboard is split, all keys go in the left half.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowKeys<T> {
+    pub left: Vec<T>,
+    pub right: Vec<T>,
+}
+
+/// Tracks which page and layer are shown, and turns key presses into actions
+pub struct KeyboardEngine {
+    config: Config,
+    /// The role of the page being shown, e.g. "default" or "symbols"
+    page: String,
+    layer: Layer,
+    /// The char of the last key tapped, when it was tapped, and how many characters it typed
+    last_tap: Option<(String, Instant, u32)>,
+    /// While a page switching key is held down: the page to go back to, when the key counts as
+    /// held, and whether another key was used on the new page
+    momentary: Option<(String, Instant, bool)>,
+    /// The content type of the focused text field
+    content_type: Option<ContentType>,
+    /// Whether rows are divided into halves at the edges of the screen
+    split: bool,
+    orientation: Orientation,
+}
+impl KeyboardEngine {
+    pub fn new(config: Config) -> Self {
+        KeyboardEngine {
+            page: "default".to_string(),
+            layer: Layer::Normal,
+            last_tap: None,
+            momentary: None,
+            content_type: None,
+            split: config.split,
+            orientation: Orientation::Landscape,
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn config_mut(&mut self) -> &mut Config {
+        &mut self.config
+    }
+
+    pub fn layer(&self) -> &Layer {
+        &self.layer
+    }
+
+    pub fn is_split(&self) -> bool {
+        self.split
+    }
+
+    pub fn orientation(&self) -> Orientation {
+        self.orientation
+    }
+
+    /// CSS classes naming the layout and page being shown, e.g. "layout-us" and "page-qwerty",
+    /// for themes to style the window, rows and keys by
+    pub fn page_classes(&self) -> Vec<String> {
+        vec![
+            format!("layout-{}", css_ident(&self.config.layout)),
+            format!("page-{}", css_ident(self.page_name())),
+        ]
+    }
+
+    /// Pick pages for the orientation of the monitor. Returns whether it changed.
+    pub fn set_orientation(&mut self, orientation: Orientation) -> bool {
+        let changed = self.orientation != orientation;
+        self.orientation = orientation;
+        changed
+    }
+
+    /// The name of the page being shown, as it appears under `[pages]`
+    pub fn page_name(&self) -> &str {
+        let layout = &self.config.layouts[&self.config.layout];
+        layout
+            .page(&self.page, self.orientation)
+            .map_or("", String::as_str)
+    }
+
+    /// The page being shown, or an empty one if the layout doesn't have it
+    pub fn page(&self) -> &PageConfig {
+        self.config.pages.get(self.page_name()).unwrap_or(&NO_PAGE)
+    }
+
+    /// The configuration for the key written as `name` in a page
+    pub fn key(&self, name: &str) -> KeyConfig {
+        key(&self.config, name)
+    }
+
+    /// The key names in each row of the current page
+    pub fn row_names(&self) -> Vec<RowKeys<&str>> {
+        self.page()
+            .keys
+            .iter()
+            .map(|row| {
+                let keys: Vec<&str> = row.split(' ').collect();
+                let is_key = |key: &&str| *key != SPLIT_SEPARATOR;
+                if !self.split {
+                    return RowKeys {
+                        left: keys.into_iter().filter(is_key).collect(),
+                        right: Vec::new(),
+                    };
+                }
+                // Split at the explicit separator if there is one, otherwise down the middle
+                let middle = keys
+                    .iter()
+                    .position(|key| *key == SPLIT_SEPARATOR)
+                    .unwrap_or(keys.len().div_ceil(2));
+                let (left, right) = keys.split_at(middle);
+                RowKeys {
+                    left: left.iter().copied().filter(is_key).collect(),
+                    right: right.iter().copied().filter(is_key).collect(),
+                }
+            })
+            .collect()
+    }
+
+    /// The keys in each row of the current page
+    pub fn rows(&self) -> Vec<RowKeys<KeyConfig>> {
+        self.row_names()
+            .into_iter()
+            .map(|row| RowKeys {
+                left: row.left.iter().map(|name| self.key(name)).collect(),
+                right: row.right.iter().map(|name| self.key(name)).collect(),
+            })
+            .collect()
+    }
+
+    /// The number of keys in the widest row (or half row, when split) of the current page
+    pub fn columns(&self) -> usize {
+        self.row_names()
+            .iter()
+            .map(|row| row.left.len().max(row.right.len()))
+            .max()
+            .unwrap_or(1)
+            .max(1)
+    }
+
+    /// The keyboard's width on a monitor `monitor_width` pixels wide
+    pub fn width(&self, monitor_width: i32) -> i32 {
+        if self.split {
+            // Split halves are anchored to the edges of the monitor
+            monitor_width - 2 * self.config.padding
+        } else {
+            self.config.width.resolve(monitor_width)
+        }
+    }
+
+    /// The keyboard's height on a monitor `monitor_height` pixels tall
+    pub fn height(&self, monitor_height: i32) -> i32 {
+        self.config.height.resolve(monitor_height)
+    }
+
+    /// Fit the keys of the current page into a keyboard of the given size
+    pub fn key_size(&self, width: i32, height: i32, monitor_width: i32) -> KeySize {
+        let spacing = self.config.key_spacing;
+        let padding = self.config.padding;
+        let rows = self.page().keys.len().max(1) as i32;
+        let columns = self.columns() as i32;
+
+        let key_height = match self.config.key_height {
+            Some(key_height) => key_height.resolve(height),
+            None => (height - 2 * padding - spacing * (rows - 1)) / rows,
+        };
+        let available_width = if self.split {
+            let gap = self.config.split_gap.resolve(monitor_width);
+            (width - gap) / 2
+        } else {
+            width - 2 * padding
+        };
+        let key_width = (available_width - spacing * (columns - 1)) / columns;
+
+        KeySize {
+            width: key_width.max(1),
+            height: key_height.max(1),
+        }
+    }
+
+    /// Show the page with the given role. Returns whether it changed.
+    pub fn set_page(&mut self, page: &str) -> bool {
+        if page == self.page {
+            return false;
+        }
+        self.page = page.to_string();
+        true
+    }
+
+    /// Adapt the keyboard to the focused text field, e.g. showing digits for a PIN and
+    /// capitalizing the start of a sentence
+    pub fn set_text_field(&mut self, field: &TextField) -> Vec<Action> {
+        let mut actions = Vec::new();
+        let content_type = field.content_type.unwrap_or_default();
+        if field.content_type != self.content_type {
+            self.content_type = field.content_type;
+            let layout = &self.config.layouts[&self.config.layout];
+            let page = match content_type.is_numeric() && layout.pages.contains_key("symbols") {
+                true => "symbols",
+                false => "default",
+            };
+            if self.set_page(page) {
+                actions.push(Action::Render);
+            }
+            let layer = match content_type.has_hint(ContentType::UPPERCASE) {
+                true => Layer::Locked,
+                false => Layer::Normal,
+            };
+            if layer != self.layer {
+                actions.push(self.change_layer(layer));
+            }
+        }
+        let starts_sentence = field
+            .surrounding_text
+            .as_ref()
+            .is_some_and(|(text, cursor)| {
+                let before = text.get(..*cursor as usize).unwrap_or_default().trim_end();
+                before.is_empty() || before.ends_with(['.', '!', '?'])
+            });
+        if content_type.has_hint(ContentType::AUTO_CAPITALIZATION)
+            && starts_sentence
+            && self.layer == Layer::Normal
+        {
+            actions.push(self.change_layer(Layer::Shifted));
+        }
+        actions
+    }
+
+    /// Handle a key going down. Page switching keys switch right away, and switch back on release
+    /// if they were held or used to type something.
+    pub fn key_down(&mut self, key: &KeyConfig, now: Instant) -> Vec<Action> {
+        // Keys that do something else when held or double tapped switch pages when tapped
+        if page_switch(key).is_none() || key.hold.is_some() || key.double_tap.is_some() {
+            return Vec::new();
+        }
+        let page = self.page.clone();
+        let actions = self.press_key(key);
+        if self.page != page {
+            self.last_tap = None;
+            let hold_time = key.hold_time.unwrap_or(self.config.hold_time);
+            self.momentary = Some((page, now + Duration::from_millis(hold_time), false));
+        }
+        actions
+    }
+
+    /// Handle every key being released, which ends a momentary page switch
+    pub fn release(&mut self, now: Instant) -> Vec<Action> {
+        let Some((page, held_at, used)) = self.momentary.take() else {
+            return Vec::new();
+        };
+        let held = now >= held_at;
+        // A quick tap leaves the new page showing
+        if (used || held) && self.set_page(&page) {
+            vec![Action::Render]
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Handle a key being tapped, which types it or does its double tap if it was just tapped
+    pub fn tap(&mut self, key: &KeyConfig, now: Instant) -> Vec<Action> {
+        if let Some((_, _, used)) = &mut self.momentary {
+            *used = true;
+        }
+        let interval = key.double_tap_time.unwrap_or(self.config.double_tap_time);
+        let interval = Duration::from_millis(interval);
+        let last_tap = self.last_tap.take();
+        if let (Some(double_tap), Some((last, time, typed))) = (&key.double_tap, last_tap) {
+            if last == key.char && now.duration_since(time) < interval {
+                let mut actions = Vec::new();
+                // Take back what the first tap typed
+                if typed > 0 {
+                    actions.push(Action::Input(InputAction::DeleteSurrounding {
+                        before: typed,
+                        after: 0,
+                    }));
+                }
+                let double_tap = self.key(double_tap);
+                actions.extend(self.press_key(&double_tap));
+                return actions;
+            }
+        }
+
+        let was_locked = self.layer == Layer::Locked;
+        let actions = self.press_key(key);
+        // Unlocking caps lock doesn't count as a first tap, so tapping shift again right away
+        // shifts instead of locking it again
+        if was_locked && self.layer != Layer::Locked {
+            return actions;
+        }
+        let typed = actions
+            .iter()
+            .map(|action| match action {
+                Action::Input(InputAction::Commit(text)) => text.chars().count() as u32,
+                _ => 0,
+            })
+            .sum();
+        self.last_tap = Some((key.char.clone(), now, typed));
+        actions
+    }
+
+    /// Handle a key being held down, which does its hold key or types its hint
+    pub fn hold(&mut self, key: &KeyConfig, now: Instant) -> Vec<Action> {
+        self.last_tap = None;
+        if let Some((_, _, used)) = &mut self.momentary {
+            *used = true;
+        }
+        match (&key.hold, &key.hint) {
+            (Some(hold), _) => {
+                let hold = self.key(hold);
+                let page = self.page.clone();
+                let actions = self.press_key(&hold);
+                // A held page switch only lasts until the key is let go
+                if self.page != page && self.momentary.is_none() {
+                    self.momentary = Some((page, now, true));
+                }
+                actions
+            }
+            (None, Some(hint)) => self.press(&hint.clone()),
+            (None, None) => Vec::new(),
+        }
+    }
+
+    /// Handle a key being pressed, doing its action if it has one
+    pub fn press_key(&mut self, key: &KeyConfig) -> Vec<Action> {
+        let Some(action) = &key.action else {
+            let character = character(key, &self.layer).clone();
+            return self.press(&character);
+        };
+        match action {
+            KeyAction::Text { text } => self.input(InputAction::Commit(text.clone())),
+            KeyAction::Keys { keys } => self.input(InputAction::Keys(keys.clone())),
+            KeyAction::Page { page } => {
+                let layout = &self.config.layouts[&self.config.layout];
+                if layout.pages.contains_key(page) && self.set_page(page) {
+                    vec![Action::Render]
+                } else {
+                    Vec::new()
+                }
+            }
+            KeyAction::Layout { layout } => {
+                let has_default = self
+                    .config
+                    .layouts
+                    .get(layout)
+                    .is_some_and(|new| new.pages.contains_key("default"));
+                if !has_default || *layout == self.config.layout {
+                    return Vec::new();
+                }
+                self.config.layout = layout.clone();
+                self.page = "default".to_string();
+                vec![Action::Render]
+            }
+            KeyAction::Command { command } => vec![Action::Spawn(command.clone())],
+        }
+    }
+
+    /// Handle a key being pressed, where `key` is what it inserts (e.g. "a" or "<shift>")
+    pub fn press(&mut self, key: &str) -> Vec<Action> {
+        match key {
+            "<shift>" => {
+                let layer = match self.layer {
+                    Layer::Normal => Layer::Shifted,
+                    Layer::Shifted | Layer::Locked => Layer::Normal,
+                };
+                vec![self.change_layer(layer)]
+            }
+            "<capslock>" => {
+                let layer = match self.layer {
+                    Layer::Locked => Layer::Normal,
+                    Layer::Normal | Layer::Shifted => Layer::Locked,
+                };
+                vec![self.change_layer(layer)]
+            }
+            "<symbols>" | "<default>" => {
+                let page = &key[1..key.len() - 1];
+                let layout = &self.config.layouts[&self.config.layout];
+                if layout.pages.contains_key(page) && self.set_page(page) {
+                    vec![Action::Render]
+                } else {
+                    Vec::new()
+                }
+            }
+            "<hide>" => vec![Action::Dismiss],
+            "<split>" => {
+                self.split = !self.split;
+                vec![Action::Render]
+            }
+            "<bksp>" => self.input(InputAction::DeleteSurrounding {
+                before: 1,
+                after: 0,
+            }),
+            text => self.input(InputAction::Commit(text.to_string())),
+        }
+    }
+
+    /// Type into the text field, which uses up a one-shot shift
+    fn input(&mut self, input: InputAction) -> Vec<Action> {
+        let mut actions = vec![Action::Input(input)];
+        // Shift only applies to one key, unless it's locked
+        if self.layer == Layer::Shifted {
+            actions.push(self.change_layer(Layer::Normal));
+        }
+        actions
+    }
+
+    /// Show the keys of a layer, without it counting as a tap
+    pub fn set_layer(&mut self, layer: Layer) {
+        self.layer = layer;
+        self.last_tap = None;
+    }
+
+    fn change_layer(&mut self, layer: Layer) -> Action {
+        self.layer = layer.clone();
+        Action::SetLayer(layer)
+    }
+}
+
+/// The configuration for the key written as `name` in a page of `config`
+pub fn key(config: &Config, name: &str) -> KeyConfig {
+    let mut key = match config.keys.get(name) {
+        Some(config) if config.char.is_empty() => KeyConfig {
+            char: name.to_string(),
+            ..config.clone()
+        },
+        Some(config) => config.clone(),
+        // Provide a default config for simple keys
+        None => KeyConfig::new(name),
+    };
+    // Shift has its own time to tap it again, and keys without their own timing use the
+    // keyboard's
+    if key.char == "<shift>" {
+        key.double_tap_time = key.double_tap_time.or(config.caps_lock_time);
+    }
+    key.hold_time.get_or_insert(config.hold_time);
+    key.double_tap_time.get_or_insert(config.double_tap_time);
+    // Shift locks into caps lock unless it's configured to do something else
+    if key.char == "<shift>" && key.action.is_none() {
+        let caps_lock = Some("<capslock>".to_string());
+        match config.caps_lock {
+            CapsLock::DoubleTap if key.double_tap.is_none() => key.double_tap = caps_lock,
+            CapsLock::LongPress if key.hold.is_none() => key.hold = caps_lock,
+            _ => {}
+        }
+    }
+    key
+}
+
+/// The role of the page a key switches to, e.g. "symbols" for `<symbols>`
+pub fn page_switch(key: &KeyConfig) -> Option<&str> {
+    match (&key.action, key.char.as_str()) {
+        (Some(KeyAction::Page { page }), _) => Some(page),
+        (Some(_), _) => None,
+        (None, "<symbols>" | "<default>") => Some(&key.char[1..key.char.len() - 1]),
+        (None, _) => None,
+    }
+}
+
+/// What a key inserts in the given layer
+pub fn character<'a>(key: &'a KeyConfig, layer: &Layer) -> &'a String {
+    match (layer, &key.upper) {
+        (Layer::Locked | Layer::Shifted, Some(c)) => c,
+        _ => &key.char,
+    }
+}
+
+/// CSS classes for a key's button, so themes can style it, including the current
+/// `page_classes`
+pub fn key_classes(
+    key: &KeyConfig,
+    layer: &Layer,
+    pressed: bool,
+    page_classes: &[String],
+) -> Vec<String> {
+    let mut classes = page_classes.to_vec();
+    if let Some(config_classes) = &key.classes {
+        classes.extend(config_classes.iter().cloned());
+    }
+    // Keys like <shift> or <bksp> that don't just insert their own text
+    if key.action.is_some() || (key.char.starts_with('<') && key.char.ends_with('>')) {
+        classes.push("special".into());
+    }
+    if let Some(class) = layer.css_class() {
+        classes.push(class.into());
+    }
+    if pressed {
+        classes.push("pressed".into());
+    }
+    if key.char == "<shift>" && *layer != Layer::Normal {
+        classes.push("modifier-active".into());
+        if *layer == Layer::Locked {
+            classes.push("suggested-action".into());
+        }
+    }
+    // Lets themes tell a one-shot shift apart from caps lock
+    match (key.char.as_str(), layer) {
+        ("<shift>", Layer::Shifted) => classes.push("shift-once".into()),
+        ("<shift>" | "<capslock>", Layer::Locked) => classes.push("shift-locked".into()),
+        _ => {}
+    }
+    classes
+}
+
+/// Replace characters that aren't valid in a CSS class selector (e.g. the "+" in "us+dvorak")
+fn css_ident(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '-',
+        })
+        .collect()
+}
+
+/// The icon shown on a key in the given layer, if it has one
+pub fn icon<'a>(key: &'a KeyConfig, layer: &Layer) -> Option<&'a str> {
+    match (layer, &key.locked_icon) {
+        (Layer::Locked, Some(icon)) => Some(icon),
+        _ => key.icon.as_deref(),
+    }
+}
+
+/// The text shown on a key in the given layer
+pub fn label<'a>(key: &'a KeyConfig, layer: &Layer) -> &'a str {
+    key.label.as_ref().unwrap_or(character(key, layer))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config;
+
+    /// An engine for `user_config` on top of the defaults
+    pub(crate) fn engine(user_config: &str) -> KeyboardEngine {
+        KeyboardEngine::new(config::from_toml(user_config).unwrap())
+    }
+
+    /// Tap the key written as `name` in a page
+    fn tap(engine: &mut KeyboardEngine, name: &str, now: Instant) -> Vec<Action> {
+        let key = engine.key(name);
+        engine.tap(&key, now)
+    }
+
+    fn commit(text: &str) -> Action {
+        Action::Input(InputAction::Commit(text.to_string()))
+    }
+
+    #[test]
+    fn types_keys() {
+        let mut engine = engine("");
+        assert_eq!(tap(&mut engine, "a", Instant::now()), vec![commit("a")]);
+        assert_eq!(
+            tap(&mut engine, "<bksp>", Instant::now()),
+            vec![Action::Input(InputAction::DeleteSurrounding {
+                before: 1,
+                after: 0
+            })]
+        );
+    }
+
+    #[test]
+    fn shift_applies_to_one_key() {
+        let mut engine = engine("");
+        let now = Instant::now();
+        assert_eq!(
+            tap(&mut engine, "<shift>", now),
+            vec![Action::SetLayer(Layer::Shifted)]
+        );
+        assert_eq!(
+            tap(&mut engine, "A", now + Duration::from_secs(1)),
+            vec![commit("A"), Action::SetLayer(Layer::Normal)]
+        );
+        assert_eq!(tap(&mut engine, "a", now), vec![commit("a")]);
+    }
+
+    #[test]
+    fn shift_twice_quickly_locks() {
+        let mut engine = engine("");
+        let now = Instant::now();
+        tap(&mut engine, "<shift>", now);
+        assert_eq!(
+            tap(&mut engine, "<shift>", now + Duration::from_millis(100)),
+            vec![Action::SetLayer(Layer::Locked)]
+        );
+        assert_eq!(tap(&mut engine, "A", now), vec![commit("A")]);
+        assert_eq!(engine.layer(), &Layer::Locked);
+        assert_eq!(
+            tap(&mut engine, "<shift>", now + Duration::from_secs(1)),
+            vec![Action::SetLayer(Layer::Normal)]
+        );
+    }
+
+    #[test]
+    fn shift_twice_slowly_unshifts() {
+        let mut engine = engine("");
+        let now = Instant::now();
+        tap(&mut engine, "<shift>", now);
+        assert_eq!(
+            tap(&mut engine, "<shift>", now + Duration::from_secs(1)),
+            vec![Action::SetLayer(Layer::Normal)]
+        );
+    }
+
+    #[test]
+    fn caps_lock_modes() {
+        let now = Instant::now();
+        let mut quick = engine("caps_lock_time = 50");
+        tap(&mut quick, "<shift>", now);
+        tap(&mut quick, "<shift>", now + Duration::from_millis(100));
+        assert_eq!(quick.layer(), &Layer::Normal);
+
+        let mut long_press = engine("caps_lock = 'long-press'");
+        let shift = long_press.key("<shift>");
+        assert_eq!(
+            long_press.hold(&shift, now),
+            vec![Action::SetLayer(Layer::Locked)]
+        );
+        tap(&mut long_press, "<shift>", now);
+        tap(&mut long_press, "<shift>", now + Duration::from_millis(100));
+        assert_eq!(long_press.layer(), &Layer::Shifted);
+
+        // Shift after unlocking is a first tap again
+        let mut unlock = engine("");
+        tap(&mut unlock, "<shift>", now);
+        tap(&mut unlock, "<shift>", now);
+        assert_eq!(unlock.layer(), &Layer::Locked);
+        tap(&mut unlock, "<shift>", now);
+        assert_eq!(unlock.layer(), &Layer::Normal);
+        assert_eq!(
+            tap(&mut unlock, "<shift>", now + Duration::from_millis(100)),
+            vec![Action::SetLayer(Layer::Shifted)]
+        );
+
+        let mut disabled = engine("caps_lock = 'disabled'");
+        let shift = disabled.key("<shift>");
+        assert_eq!(disabled.hold(&shift, now), vec![]);
+        tap(&mut disabled, "<shift>", now);
+        tap(&mut disabled, "<shift>", now + Duration::from_millis(100));
+        assert_eq!(disabled.layer(), &Layer::Normal);
+    }
+
+    #[test]
+    fn missing_pages() {
+        let mut engine = engine(
+            "
+            layout = 'tiny'
+            [layouts.tiny]
+            default = 'tiny'
+            [layouts.broken]
+            symbols = 'tiny'
+            [pages.tiny]
+            keys = ['a <symbols> <broken>']
+            [keys]
+            '<broken>'.action = { type = 'layout', layout = 'broken' }
+            ",
+        );
+        // The layout has no symbols page to switch to
+        assert_eq!(tap(&mut engine, "<symbols>", Instant::now()), vec![]);
+        assert_eq!(engine.page_name(), "tiny");
+        // And the other layout has no default page to start on
+        assert_eq!(tap(&mut engine, "<broken>", Instant::now()), vec![]);
+        assert_eq!(engine.page().keys, ["a <symbols> <broken>"]);
+    }
+
+    #[test]
+    fn switches_pages() {
+        let mut engine = engine("");
+        assert_eq!(engine.page_name(), "qwerty");
+        assert_eq!(
+            tap(&mut engine, "<symbols>", Instant::now()),
+            vec![Action::Render]
+        );
+        assert_eq!(engine.page_name(), "symbols");
+        assert_eq!(tap(&mut engine, "<symbols>", Instant::now()), vec![]);
+        assert_eq!(
+            tap(&mut engine, "<default>", Instant::now()),
+            vec![Action::Render]
+        );
+        assert_eq!(engine.page_name(), "qwerty");
+    }
+
+    #[test]
+    fn key_actions() {
+        let mut engine = engine(
+            "
+            [keys]
+            '.com'.action = { type = 'text', text = '.com' }
+            '<copy>'.action = { type = 'keys', keys = ['ctrl+c'] }
+            '<sym>'.action = { type = 'page', page = 'symbols' }
+            '<missing>'.action = { type = 'page', page = 'missing' }
+            '<dvorak>'.action = { type = 'layout', layout = 'us+dvorak' }
+            '<term>'.action = { type = 'command', command = ['foot', '-e', 'htop'] }
+            ",
+        );
+        let mut press = |name: &str| {
+            let key = engine.key(name);
+            engine.tap(&key, Instant::now())
+        };
+        assert_eq!(press(".com"), vec![commit(".com")]);
+        let copy = press("<copy>");
+        let [Action::Input(InputAction::Keys(combos))] = copy.as_slice() else {
+            panic!("Expected keys, got {:?}", copy);
+        };
+        assert_eq!(combos[0].modifiers, vec![config::Modifier::Ctrl]);
+        assert_eq!(combos[0].key, "c");
+        assert_eq!(press("<missing>"), vec![]);
+        assert_eq!(press("<sym>"), vec![Action::Render]);
+        assert_eq!(
+            press("<term>"),
+            vec![Action::Spawn(vec![
+                "foot".to_string(),
+                "-e".to_string(),
+                "htop".to_string()
+            ])]
+        );
+        assert_eq!(press("<dvorak>"), vec![Action::Render]);
+        assert_eq!(engine.page_name(), "dvorak");
+        // Keys without a char are labelled with their name
+        assert_eq!(engine.key("<copy>").char, "<copy>");
+    }
+
+    #[test]
+    fn hold() {
+        let mut engine = engine(
+            "
+            [keys]
+            ','.hold = ';'
+            '<space>' = { char = ' ', hold = '<symbols>' }
+            ",
+        );
+        let now = Instant::now();
+        let comma = engine.key(",");
+        assert_eq!(engine.hold(&comma, now), vec![commit(";")]);
+        // Holding a page switch shows the page until the key is let go
+        let space = engine.key("<space>");
+        assert_eq!(engine.hold(&space, now), vec![Action::Render]);
+        assert_eq!(engine.page_name(), "symbols");
+        assert_eq!(engine.release(now), vec![Action::Render]);
+        assert_eq!(engine.page_name(), "qwerty");
+        // Keys without a hold key type their hint
+        let q = engine.key("q");
+        assert_eq!(engine.hold(&q, now), vec![commit("1")]);
+        assert_eq!(engine.release(now), vec![]);
+    }
+
+    #[test]
+    fn double_tap() {
+        let mut engine = engine("[keys]\n'.'.double_tap = '<period>'\n'<period>'.char = '. '");
+        let now = Instant::now();
+        assert_eq!(tap(&mut engine, ".", now), vec![commit(".")]);
+        // The first tap's "." is replaced
+        assert_eq!(
+            tap(&mut engine, ".", now + Duration::from_millis(100)),
+            vec![
+                Action::Input(InputAction::DeleteSurrounding {
+                    before: 1,
+                    after: 0
+                }),
+                commit(". ")
+            ]
+        );
+        // A third tap starts over
+        assert_eq!(
+            tap(&mut engine, ".", now + Duration::from_millis(200)),
+            vec![commit(".")]
+        );
+        // Tapping too slowly or tapping other keys in between doesn't count
+        assert_eq!(
+            tap(&mut engine, ".", now + Duration::from_secs(1)),
+            vec![commit(".")]
+        );
+        tap(&mut engine, "a", now + Duration::from_secs(1));
+        assert_eq!(
+            tap(&mut engine, ".", now + Duration::from_secs(1)),
+            vec![commit(".")]
+        );
+    }
+
+    #[test]
+    fn adapts_to_text_fields() {
+        let mut engine = engine("");
+        let field = |text: &str, hint, purpose| TextField {
+            seat: None,
+            surrounding_text: Some((text.to_string(), text.len() as u32)),
+            content_type: Some(ContentType { hint, purpose }),
+        };
+        let pin = 9;
+        assert_eq!(
+            engine.set_text_field(&field("", 0, pin)),
+            vec![Action::Render]
+        );
+        assert_eq!(engine.page_name(), "symbols");
+        // Other text fields go back to letters
+        assert_eq!(
+            engine.set_text_field(&field("", 0, 0)),
+            vec![Action::Render]
+        );
+
+        let sentences = ContentType::AUTO_CAPITALIZATION;
+        assert_eq!(
+            engine.set_text_field(&field("Hi. ", sentences, 0)),
+            vec![Action::SetLayer(Layer::Shifted)]
+        );
+        tap(&mut engine, "A", Instant::now());
+        assert_eq!(engine.set_text_field(&field("Hi. A", sentences, 0)), vec![]);
+        assert_eq!(engine.layer(), &Layer::Normal);
+
+        assert_eq!(
+            engine.set_text_field(&field("", ContentType::UPPERCASE, 0)),
+            vec![Action::SetLayer(Layer::Locked)]
+        );
+    }
+
+    #[test]
+    fn swipes() {
+        assert_eq!(swipe(0.0, 10.0), None);
+        assert_eq!(swipe(5.0, 40.0), Some(Swipe::Hint));
+        // Sideways drags aren't swipes down
+        assert_eq!(swipe(60.0, 40.0), None);
+        // Long swipes dismiss the keyboard, even when they start on a key
+        assert_eq!(swipe(10.0, 150.0), Some(Swipe::Dismiss));
+        assert_eq!(swipe(100.0, 150.0), Some(Swipe::Hint));
+    }
+
+    #[test]
+    fn per_key_timing() {
+        let mut engine = engine(
+            "hold_time = 400\n[keys]\n'.'.double_tap = ','\n'.'.double_tap_time = 50\n\
+             '<symbols>'.hold_time = 800",
+        );
+        let now = Instant::now();
+        assert_eq!(engine.key("a").hold_time, Some(400));
+        assert_eq!(engine.key("a").double_tap_time, Some(500));
+        // Too slow for the key's own double tap time, though not for the keyboard's
+        tap(&mut engine, ".", now);
+        assert_eq!(
+            tap(&mut engine, ".", now + Duration::from_millis(100)),
+            vec![commit(".")]
+        );
+        // Held for the keyboard's hold time, but not the key's
+        let symbols = engine.key("<symbols>");
+        engine.key_down(&symbols, now);
+        assert_eq!(engine.release(now + Duration::from_millis(600)), vec![]);
+        assert_eq!(engine.page_name(), "symbols");
+    }
+
+    #[test]
+    fn momentary_pages() {
+        let mut engine = engine("layout = 'us'");
+        let now = Instant::now();
+        let symbols = engine.key("<symbols>");
+        let default = engine.key("<default>");
+
+        // A quick tap switches pages for good
+        assert_eq!(engine.key_down(&symbols, now), vec![Action::Render]);
+        assert_eq!(engine.release(now + Duration::from_millis(100)), vec![]);
+        assert_eq!(engine.page_name(), "symbols");
+        engine.key_down(&default, now);
+        engine.release(now);
+        assert_eq!(engine.page_name(), "qwerty");
+
+        // Holding it only shows the page while it's down
+        engine.key_down(&symbols, now);
+        assert_eq!(engine.page_name(), "symbols");
+        assert_eq!(
+            engine.release(now + Duration::from_secs(1)),
+            vec![Action::Render]
+        );
+        assert_eq!(engine.page_name(), "qwerty");
+
+        // So does typing on the page while it's down
+        engine.key_down(&symbols, now);
+        tap(&mut engine, "1", now);
+        assert_eq!(engine.release(now), vec![Action::Render]);
+        assert_eq!(engine.page_name(), "qwerty");
+
+        // Other keys don't switch pages on the way down
+        let a = engine.key("a");
+        assert_eq!(engine.key_down(&a, now), vec![]);
+        assert_eq!(engine.release(now), vec![]);
+    }
+
+    #[test]
+    fn pages_follow_layout() {
+        let engine = engine("layout = 'us+dvorak'");
+        assert_eq!(engine.page_name(), "dvorak");
+    }
+
+    #[test]
+    fn pages_follow_orientation() {
+        let mut engine = engine(
+            "
+            [layouts.us.portrait]
+            default = 'narrow'
+            [pages.narrow]
+            keys = ['a b']
+            ",
+        );
+        assert_eq!(engine.page_name(), "qwerty");
+        assert!(engine.set_orientation(Orientation::Portrait));
+        assert!(!engine.set_orientation(Orientation::Portrait));
+        assert_eq!(engine.page_name(), "narrow");
+        // Roles without an override fall back to the layout's page
+        engine.set_page("symbols");
+        assert_eq!(engine.page_name(), "symbols");
+    }
+
+    #[test]
+    fn key_config() {
+        let engine = engine("");
+        let space = engine.key("<space>");
+        assert_eq!(space.char, " ");
+        let q = engine.key("q");
+        assert_eq!(q.char, "q");
+        assert_eq!(q.hint.as_deref(), Some("1"));
+        // Keys that aren't configured type themselves
+        assert_eq!(engine.key("ß").char, "ß");
+    }
+
+    #[test]
+    fn characters_and_labels() {
+        let mut key = KeyConfig::new("a");
+        key.upper = Some("A".to_string());
+        assert_eq!(character(&key, &Layer::Normal), "a");
+        assert_eq!(character(&key, &Layer::Shifted), "A");
+        assert_eq!(character(&key, &Layer::Locked), "A");
+        assert_eq!(label(&key, &Layer::Shifted), "A");
+        key.label = Some("letter".to_string());
+        assert_eq!(label(&key, &Layer::Shifted), "letter");
+        // Keys without an upper case type the same thing when shifted
+        assert_eq!(character(&KeyConfig::new("1"), &Layer::Shifted), "1");
+    }
+
+    #[test]
+    fn locked_shift_looks_different() {
+        let engine = engine("");
+        let shift = engine.key("<shift>");
+        assert_eq!(icon(&shift, &Layer::Shifted), Some("go-up"));
+        assert_eq!(icon(&shift, &Layer::Locked), Some("go-top"));
+        let shifted = key_classes(&shift, &Layer::Shifted, false, &[]);
+        assert!(shifted.contains(&"shift-once".to_string()));
+        let locked = key_classes(&shift, &Layer::Locked, false, &[]);
+        assert!(locked.contains(&"shift-locked".to_string()));
+        assert!(!locked.contains(&"shift-once".to_string()));
+    }
+
+    #[test]
+    fn page_classes() {
+        let mut engine = engine("layout = 'us+dvorak'");
+        assert_eq!(engine.page_classes(), ["layout-us-dvorak", "page-dvorak"]);
+        tap(&mut engine, "<symbols>", Instant::now());
+        let page_classes = engine.page_classes();
+        assert_eq!(page_classes[1], "page-symbols");
+        let a = engine.key("a");
+        assert!(key_classes(&a, &Layer::Normal, false, &page_classes).contains(&page_classes[1]));
+    }
+
+    fn names(engine: &KeyboardEngine) -> Vec<(String, String)> {
+        engine
+            .row_names()
+            .into_iter()
+            .map(|row| (row.left.join(" "), row.right.join(" ")))
+            .collect()
+    }
+
+    #[test]
+    fn split_rows() {
+        let mut engine = engine(
+            "
+            [layouts.us]
+            default = 'test'
+            [pages.test]
+            keys = ['a b c d e', 'f g <gap> h', 'i <gap> j k l', '<split> |']
+            ",
+        );
+        let row = |left: &str, right: &str| (left.to_string(), right.to_string());
+        assert_eq!(
+            names(&engine),
+            vec![
+                row("a b c d e", ""),
+                row("f g h", ""),
+                row("i j k l", ""),
+                row("<split> |", "")
+            ]
+        );
+        assert_eq!(engine.columns(), 5);
+
+        assert_eq!(
+            tap(&mut engine, "<split>", Instant::now()),
+            vec![Action::Render]
+        );
+        assert!(engine.is_split());
+        assert_eq!(
+            names(&engine),
+            vec![
+                row("a b c", "d e"),
+                row("f g", "h"),
+                row("i", "j k l"),
+                row("<split>", "|")
+            ]
+        );
+        assert_eq!(engine.columns(), 3);
+    }
+
+    #[test]
+    fn rows_use_key_config() {
+        let engine = engine("");
+        let rows = engine.rows();
+        assert_eq!(rows.len(), 4);
+        let last = rows.last().unwrap();
+        assert_eq!(last.left[1].char, " ");
+        assert!(last.right.is_empty());
+    }
+
+    #[test]
+    fn hide() {
+        let mut engine = engine("");
+        assert_eq!(
+            tap(&mut engine, "<hide>", Instant::now()),
+            vec![Action::Dismiss]
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    thread,
+};
+
+use gtk::glib;
+use serde::{de::IntoDeserializer, Deserialize};
+
+use dweeb::config::{Anchor, OutputSelection, ShellLayer};
+
+/// Commands that can be sent to a running keyboard with `dweeb msg <command>`
+#[derive(Debug, Clone)]
+pub enum IpcCommand {
+    /// Move the keyboard to a named output, or follow the focused text field with "focused"
+    Output(OutputSelection),
+    /// Attach the keyboard to the top or bottom of the screen
+    Anchor(Anchor),
+    /// Show the keyboard in the "top" or "overlay" layer
+    Layer(ShellLayer),
+    /// Push other windows out of the way ("on") or cover them ("off")
+    Exclusive(bool),
+}
+impl IpcCommand {
+    fn parse(line: &str) -> Result<Self, String> {
+        let mut words = line.split_whitespace();
+        let command = words.next().ok_or("Empty command")?;
+        let mut argument = || {
+            words
+                .next()
+                .ok_or_else(|| format!("Missing argument for '{}'", command))
+        };
+        match command {
+            "output" => Ok(IpcCommand::Output(argument()?.to_string().into())),
+            "anchor" => Ok(IpcCommand::Anchor(parse_value(argument()?)?)),
+            "layer" => Ok(IpcCommand::Layer(parse_value(argument()?)?)),
+            "exclusive" => match argument()? {
+                "on" | "true" => Ok(IpcCommand::Exclusive(true)),
+                "off" | "false" => Ok(IpcCommand::Exclusive(false)),
+                value => Err(format!("Expected 'on' or 'off', got '{}'", value)),
+            },
+            _ => Err(format!("Unknown command '{}'", command)),
+        }
+    }
+}
+
+/// Parse a value the same way it would be read from the config file
+fn parse_value<'de, T: Deserialize<'de>>(value: &'de str) -> Result<T, String> {
+    T::deserialize(IntoDeserializer::<serde::de::value::Error>::into_deserializer(value))
+        .map_err(|e| e.to_string())
+}
+
+/// The socket of the keyboard running on this Wayland display, so keyboards in other sessions
+/// sharing the runtime directory don't clash
+fn socket_path() -> PathBuf {
+    let display = std::env::var_os("WAYLAND_DISPLAY").unwrap_or_else(|| "wayland-0".into());
+    // The display can also be given as an absolute path to its socket
+    let display = Path::new(&display)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    dirs::runtime_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join(format!("dweeb-{}.sock", display))
+}
+
+/// Listen for commands from `dweeb msg` in the background, forwarding them to the GUI
+pub fn listen(send_to_gtk: glib::Sender<IpcCommand>) -> Result<(), String> {
+    let path = socket_path();
+    // Clean up after a previous instance which didn't exit cleanly
+    if UnixStream::connect(&path).is_err() {
+        let _ = std::fs::remove_file(&path);
+    }
+    let listener = UnixListener::bind(&path)
+        .map_err(|e| format!("Could not listen on {}: {}", path.to_string_lossy(), e))?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let Ok(reader) = stream.try_clone() else {
+                continue;
+            };
+            let mut stream = stream;
+            for line in BufReader::new(reader).lines().map_while(Result::ok) {
+                let response = IpcCommand::parse(&line).and_then(|command| {
+                    send_to_gtk
+                        .send(command)
+                        .map_err(|_| "The keyboard is shutting down".to_string())
+                });
+                let _ = match response {
+                    Ok(()) => writeln!(stream, "ok"),
+                    Err(e) => writeln!(stream, "error: {}", e),
+                };
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Send a command to the running keyboard
+pub fn send(args: &[String]) -> Result<(), String> {
+    let path = socket_path();
+    let mut stream = UnixStream::connect(&path).map_err(|e| {
+        format!(
+            "Could not connect to dweeb at {}, is it running? ({})",
+            path.to_string_lossy(),
+            e
+        )
+    })?;
+    writeln!(stream, "{}", args.join(" ")).map_err(|e| e.to_string())?;
+
+    let mut response = String::new();
+    BufReader::new(stream)
+        .read_line(&mut response)
+        .map_err(|e| e.to_string())?;
+    match response.trim().strip_prefix("error: ") {
+        Some(e) => Err(e.to_string()),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,12 @@
+//! Everything in dweeb that doesn't depend on GTK: configuration, the keyboard's state, and
+//! talking to the compositor. GTK is only needed by the binary, behind the default `gui`
+//! feature, so `cargo test --no-default-features` works without GTK installed.
+
+pub mod check;
+pub mod config;
+pub mod engine;
+#[cfg(test)]
+mod mock_compositor;
+pub mod render;
+pub mod session;
+pub mod wayland;
@@ -1,103 +1,60 @@
-use std::{thread, time::Duration};
+use std::{cell::RefCell, rc::Rc, thread, time::Duration};
 
+use dweeb::{config, session::run_wayland_thread, wayland::InputAction};
 use gtk::glib;
 
-mod config;
+mod commands;
+mod ipc;
 mod ui;
-mod wayland;
 
 use relm4::RelmApp;
-use tokio::{
-    io::unix::AsyncFd,
-    sync::mpsc::{unbounded_channel, UnboundedReceiver},
-};
+use tokio::sync::mpsc::unbounded_channel;
 use ui::app::AppModel;
-use wayland::KeyboardWriter;
 
-#[tokio::main(flavor = "current_thread")]
-async fn run_wayland_thread(
-    mut recv_from_gtk: UnboundedReceiver<String>,
-    send_to_gtk: glib::Sender<bool>,
-) {
-    let conn = wayland_client::Connection::connect_to_env()
-        .map_err(|_| "Could not connect to wayland socket.")
-        .unwrap();
-    let wl_display = conn.display();
-    let mut event_queue = conn.new_event_queue();
-    let _registry = wl_display.get_registry(&event_queue.handle(), ());
-    let mut writer = KeyboardWriter::new(&mut event_queue);
-
-    let mut was_active = writer.is_active();
-
-    loop {
-        // This would be required if other threads were reading from the socket.
-        // event_queue.dispatch_pending(&mut state).unwrap();
-        let read_guard = event_queue.prepare_read().unwrap();
-        let fd = read_guard.connection_fd();
-        let async_fd = AsyncFd::new(fd).unwrap();
-
-        tokio::select! {
-            keymsg = recv_from_gtk.recv() => {
-                match keymsg {
-                    Some(key) => writer.send_key(key),
-                    None => {
-                        // Receiver is dead -- all senders are dropped.
-                    }
-                }
-            },
-            async_guard = async_fd.readable() => {
-                async_guard.unwrap().clear_ready();
-                // Drop the async_fd since it's holding a reference to the read_guard,
-                // which is dropped on read. We don't need to read from it anyways.
-                std::mem::drop(async_fd);
-                // This should not block because we already ensured readiness
-                let event = read_guard.read();
-                match event {
-                    // There are events but another thread processed them, we don't need to dispatch
-                    Ok(0) => {}
-                    // We have some events
-                    Ok(_) => {
-                        event_queue.dispatch_pending(&mut writer).unwrap();
-                    }
-                    // No events to receive
-                    Err(_) => {} // Err(e) => eprintln!("{}", e),
-                }
-
-                let is_active = writer.is_active();
-                if was_active != is_active {
-                    if is_active {
-                        // Immediately open the keyboard
-                        send_to_gtk.send(true).unwrap();
-                    }
-                    was_active = is_active;
-                }
-            },
-            // Debounce closing the keyboard.
-            // This prevents the keyboard from flashing in and out epileptically when switching
-            // between inputs in some implementations (e.g. hyprland).
-            _ = tokio::time::sleep(Duration::from_millis(400)) => {
-                if !was_active {
-                    send_to_gtk.send(false).unwrap();
-                }
-            },
-        }
-
-        // Send any new messages to the socket.
-        event_queue.flush().unwrap();
+fn main() -> Result<(), String> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        // Control an already running keyboard
+        Some("msg") => return ipc::send(&args[1..]),
+        // Preview a layout without a Wayland session
+        Some("render") => return commands::render(&args[1..]),
+        // Lint a configuration, e.g. in CI
+        Some("check") => return commands::check(&args[1..]),
+        // Describe config.toml for editors
+        Some("schema") => return commands::schema(),
+        Some(command) => return Err(format!("Unknown command '{}'", command)),
+        None => {}
     }
-}
 
-fn main() -> Result<(), String> {
     let config = config::get_config()?;
 
     let (send_to_gtk, recv_from_wl) = glib::MainContext::channel(glib::source::PRIORITY_DEFAULT);
-    let (send_to_wl, recv_from_gtk) = unbounded_channel::<String>();
+    let (send_to_wl, recv_from_gtk) = unbounded_channel::<InputAction>();
+    let (send_ipc_to_gtk, recv_from_ipc) =
+        glib::MainContext::channel(glib::source::PRIORITY_DEFAULT);
 
     // This has to come before the GUI app is initialized
-    thread::spawn(move || run_wayland_thread(recv_from_gtk, send_to_gtk));
-
+    let close_delay = Duration::from_millis(config.close_delay);
+    let seat = config.seat.clone();
+    thread::spawn(move || {
+        let send_to_gtk = move |event| send_to_gtk.send(event).is_ok();
+        run_wayland_thread(recv_from_gtk, send_to_gtk, close_delay, seat)
+    });
+    ipc::listen(send_ipc_to_gtk)?;
+
+    // Filled in by the GUI if it quits because the Wayland thread gave up
+    let fatal_error = Rc::new(RefCell::new(None));
     let app = RelmApp::new("org.smona.keyboard");
-    app.run::<AppModel>((send_to_wl, recv_from_wl, config));
-
-    Ok(())
+    app.run::<AppModel>((
+        send_to_wl,
+        recv_from_wl,
+        recv_from_ipc,
+        config,
+        fatal_error.clone(),
+    ));
+
+    match fatal_error.take() {
+        Some(error) => Err(error.to_string()),
+        None => Ok(()),
+    }
 }
@@ -0,0 +1,660 @@
+//! A fake compositor which runs in the test process, so the Wayland side of dweeb can be tested
+//! without a real compositor. It only implements the globals dweeb uses, and records the
+//! requests dweeb makes.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Read,
+    os::unix::net::UnixStream,
+    sync::{mpsc, Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use wayland_client::Connection;
+pub use wayland_protocols::wp::text_input::zv3::server::zwp_text_input_v3::{
+    ContentHint, ContentPurpose,
+};
+use wayland_protocols_misc::{
+    zwp_input_method_v2::server::{
+        zwp_input_method_manager_v2::{self, ZwpInputMethodManagerV2},
+        zwp_input_method_v2::{self, ZwpInputMethodV2},
+        zwp_input_popup_surface_v2::{self, ZwpInputPopupSurfaceV2},
+    },
+    zwp_virtual_keyboard_v1::server::{
+        zwp_virtual_keyboard_manager_v1::{self, ZwpVirtualKeyboardManagerV1},
+        zwp_virtual_keyboard_v1::{self, ZwpVirtualKeyboardV1},
+    },
+};
+use wayland_server::{
+    backend::{ClientData, GlobalId},
+    protocol::{
+        wl_buffer::{self, WlBuffer},
+        wl_compositor::{self, WlCompositor},
+        wl_output::{self, WlOutput},
+        wl_region::{self, WlRegion},
+        wl_seat::{self, WlSeat},
+        wl_shm::{self, WlShm},
+        wl_shm_pool::{self, WlShmPool},
+        wl_surface::{self, WlSurface},
+    },
+    Client, DataInit, Dispatch, Display, DisplayHandle, GlobalDispatch, New, Resource,
+};
+
+/// How long to wait for dweeb before failing a test
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The connector names of the compositor's outputs
+pub const OUTPUTS: [&str; 2] = ["DP-1", "HDMI-A-1"];
+
+/// A request dweeb made to an input method or virtual keyboard
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    CommitString {
+        seat: String,
+        text: String,
+    },
+    DeleteSurroundingText {
+        seat: String,
+        before: u32,
+        after: u32,
+    },
+    Commit {
+        seat: String,
+        serial: u32,
+    },
+    Keymap {
+        seat: String,
+        keymap: String,
+    },
+    Key {
+        seat: String,
+        key: u32,
+        pressed: bool,
+    },
+    Modifiers {
+        seat: String,
+        depressed: u32,
+    },
+}
+
+/// Things a test can make the compositor do
+enum Command {
+    AddSeat(String),
+    RemoveSeat(String),
+    Activate(String),
+    /// Focus a text field with the given text, all at once
+    ActivateWithText(String, String),
+    Deactivate(String),
+    SurroundingText(String, String, u32),
+    ContentType(String, ContentHint, ContentPurpose),
+    /// Show the input popups of a seat on an output
+    ShowPopup(String, String),
+    Unavailable(String),
+}
+
+struct Compositor {
+    seats: HashMap<String, GlobalId>,
+    /// Input methods with the name of their seat
+    input_methods: Vec<(String, ZwpInputMethodV2)>,
+    /// Input popup surfaces with the name of their seat
+    popups: Vec<(String, WlSurface)>,
+    /// Outputs the client bound, with their names
+    outputs: Vec<(String, WlOutput)>,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+impl Compositor {
+    fn handle(&mut self, display: &DisplayHandle, command: Command) {
+        match command {
+            Command::AddSeat(name) => {
+                let global = display.create_global::<Compositor, WlSeat, String>(5, name.clone());
+                self.seats.insert(name, global);
+            }
+            Command::RemoveSeat(name) => {
+                if let Some(global) = self.seats.remove(&name) {
+                    display.remove_global::<Compositor>(global);
+                }
+            }
+            Command::Activate(seat) => self.send(&seat, |im| {
+                im.activate();
+                im.done();
+            }),
+            Command::ActivateWithText(seat, text) => self.send(&seat, |im| {
+                im.activate();
+                im.surrounding_text(text.clone(), text.len() as u32, text.len() as u32);
+                im.done();
+            }),
+            Command::Deactivate(seat) => self.send(&seat, |im| {
+                im.deactivate();
+                im.done();
+            }),
+            Command::SurroundingText(seat, text, cursor) => self.send(&seat, |im| {
+                im.surrounding_text(text.clone(), cursor, cursor);
+                im.done();
+            }),
+            Command::ContentType(seat, hint, purpose) => self.send(&seat, |im| {
+                im.content_type(hint, purpose);
+                im.done();
+            }),
+            Command::ShowPopup(seat, output) => {
+                let outputs = self.outputs.iter().filter(|(name, _)| *name == output);
+                for (_, output) in outputs {
+                    for (_, surface) in self.popups.iter().filter(|(name, _)| *name == seat) {
+                        surface.enter(output);
+                    }
+                }
+            }
+            Command::Unavailable(seat) => self.send(&seat, |im| im.unavailable()),
+        }
+    }
+
+    /// Send events to every input method on `seat`
+    fn send(&self, seat: &str, send: impl Fn(&ZwpInputMethodV2)) {
+        for (_, im) in self.input_methods.iter().filter(|(name, _)| name == seat) {
+            send(im);
+        }
+    }
+
+    fn record(&self, request: Request) {
+        self.requests.lock().unwrap().push(request);
+    }
+}
+
+struct ClientState;
+impl ClientData for ClientState {}
+
+impl GlobalDispatch<WlSeat, String> for Compositor {
+    fn bind(
+        _: &mut Self,
+        _: &DisplayHandle,
+        _: &Client,
+        resource: New<WlSeat>,
+        name: &String,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let seat = data_init.init(resource, name.clone());
+        seat.name(name.clone());
+    }
+}
+
+impl Dispatch<WlSeat, String> for Compositor {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &WlSeat,
+        _: wl_seat::Request,
+        _: &String,
+        _: &DisplayHandle,
+        _: &mut DataInit<'_, Self>,
+    ) {
+    }
+}
+
+impl GlobalDispatch<ZwpInputMethodManagerV2, ()> for Compositor {
+    fn bind(
+        _: &mut Self,
+        _: &DisplayHandle,
+        _: &Client,
+        resource: New<ZwpInputMethodManagerV2>,
+        _: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl Dispatch<ZwpInputMethodManagerV2, ()> for Compositor {
+    fn request(
+        state: &mut Self,
+        _: &Client,
+        _: &ZwpInputMethodManagerV2,
+        request: zwp_input_method_manager_v2::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let zwp_input_method_manager_v2::Request::GetInputMethod { seat, input_method } = request
+        {
+            let name = seat.data::<String>().cloned().unwrap_or_default();
+            let im = data_init.init(input_method, name.clone());
+            state.input_methods.push((name, im));
+        }
+    }
+}
+
+impl Dispatch<ZwpInputMethodV2, String> for Compositor {
+    fn request(
+        state: &mut Self,
+        _: &Client,
+        resource: &ZwpInputMethodV2,
+        request: zwp_input_method_v2::Request,
+        seat: &String,
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let seat = seat.clone();
+        match request {
+            zwp_input_method_v2::Request::CommitString { text } => {
+                state.record(Request::CommitString { seat, text })
+            }
+            zwp_input_method_v2::Request::DeleteSurroundingText {
+                before_length,
+                after_length,
+            } => state.record(Request::DeleteSurroundingText {
+                seat,
+                before: before_length,
+                after: after_length,
+            }),
+            zwp_input_method_v2::Request::Commit { serial } => {
+                state.record(Request::Commit { seat, serial })
+            }
+            zwp_input_method_v2::Request::GetInputPopupSurface { id, surface } => {
+                data_init.init(id, ());
+                state.popups.push((seat, surface));
+            }
+            zwp_input_method_v2::Request::Destroy => {
+                state.input_methods.retain(|(_, im)| im != resource);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl GlobalDispatch<WlOutput, String> for Compositor {
+    fn bind(
+        state: &mut Self,
+        _: &DisplayHandle,
+        _: &Client,
+        resource: New<WlOutput>,
+        name: &String,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let output = data_init.init(resource, ());
+        output.name(name.clone());
+        output.done();
+        state.outputs.push((name.clone(), output));
+    }
+}
+
+impl GlobalDispatch<WlCompositor, ()> for Compositor {
+    fn bind(
+        _: &mut Self,
+        _: &DisplayHandle,
+        _: &Client,
+        resource: New<WlCompositor>,
+        _: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl Dispatch<WlCompositor, ()> for Compositor {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &WlCompositor,
+        request: wl_compositor::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        match request {
+            wl_compositor::Request::CreateSurface { id } => {
+                data_init.init(id, ());
+            }
+            wl_compositor::Request::CreateRegion { id } => {
+                data_init.init(id, ());
+            }
+            _ => {}
+        }
+    }
+}
+
+impl GlobalDispatch<WlShm, ()> for Compositor {
+    fn bind(
+        _: &mut Self,
+        _: &DisplayHandle,
+        _: &Client,
+        resource: New<WlShm>,
+        _: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl Dispatch<WlShm, ()> for Compositor {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &WlShm,
+        request: wl_shm::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let wl_shm::Request::CreatePool { id, .. } = request {
+            data_init.init(id, ());
+        }
+    }
+}
+
+impl Dispatch<WlShmPool, ()> for Compositor {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &WlShmPool,
+        request: wl_shm_pool::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let wl_shm_pool::Request::CreateBuffer { id, .. } = request {
+            data_init.init(id, ());
+        }
+    }
+}
+
+impl Dispatch<WlSurface, ()> for Compositor {
+    fn request(
+        state: &mut Self,
+        _: &Client,
+        resource: &WlSurface,
+        request: wl_surface::Request,
+        _: &(),
+        _: &DisplayHandle,
+        _: &mut DataInit<'_, Self>,
+    ) {
+        if let wl_surface::Request::Destroy = request {
+            state.popups.retain(|(_, surface)| surface != resource);
+        }
+    }
+}
+
+impl Dispatch<WlOutput, ()> for Compositor {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &WlOutput,
+        _: wl_output::Request,
+        _: &(),
+        _: &DisplayHandle,
+        _: &mut DataInit<'_, Self>,
+    ) {
+    }
+}
+
+impl Dispatch<WlRegion, ()> for Compositor {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &WlRegion,
+        _: wl_region::Request,
+        _: &(),
+        _: &DisplayHandle,
+        _: &mut DataInit<'_, Self>,
+    ) {
+    }
+}
+
+impl Dispatch<WlBuffer, ()> for Compositor {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &WlBuffer,
+        _: wl_buffer::Request,
+        _: &(),
+        _: &DisplayHandle,
+        _: &mut DataInit<'_, Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwpInputPopupSurfaceV2, ()> for Compositor {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &ZwpInputPopupSurfaceV2,
+        _: zwp_input_popup_surface_v2::Request,
+        _: &(),
+        _: &DisplayHandle,
+        _: &mut DataInit<'_, Self>,
+    ) {
+    }
+}
+
+impl GlobalDispatch<ZwpVirtualKeyboardManagerV1, ()> for Compositor {
+    fn bind(
+        _: &mut Self,
+        _: &DisplayHandle,
+        _: &Client,
+        resource: New<ZwpVirtualKeyboardManagerV1>,
+        _: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl Dispatch<ZwpVirtualKeyboardManagerV1, ()> for Compositor {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &ZwpVirtualKeyboardManagerV1,
+        request: zwp_virtual_keyboard_manager_v1::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let zwp_virtual_keyboard_manager_v1::Request::CreateVirtualKeyboard { seat, id } =
+            request
+        {
+            let name = seat.data::<String>().cloned().unwrap_or_default();
+            data_init.init(id, name);
+        }
+    }
+}
+
+impl Dispatch<ZwpVirtualKeyboardV1, String> for Compositor {
+    fn request(
+        state: &mut Self,
+        _: &Client,
+        _: &ZwpVirtualKeyboardV1,
+        request: zwp_virtual_keyboard_v1::Request,
+        seat: &String,
+        _: &DisplayHandle,
+        _: &mut DataInit<'_, Self>,
+    ) {
+        let seat = seat.clone();
+        match request {
+            zwp_virtual_keyboard_v1::Request::Keymap { fd, size, .. } => {
+                let mut keymap = String::new();
+                File::from(fd).read_to_string(&mut keymap).unwrap();
+                assert_eq!(keymap.len(), size as usize);
+                let keymap = keymap.trim_end_matches('\0').to_string();
+                state.record(Request::Keymap { seat, keymap })
+            }
+            zwp_virtual_keyboard_v1::Request::Key {
+                key,
+                state: pressed,
+                ..
+            } => state.record(Request::Key {
+                seat,
+                key,
+                pressed: pressed == 1,
+            }),
+            zwp_virtual_keyboard_v1::Request::Modifiers { mods_depressed, .. } => {
+                state.record(Request::Modifiers {
+                    seat,
+                    depressed: mods_depressed,
+                })
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A compositor with one client connected to it. Dropping it disconnects the client.
+pub struct MockCompositor {
+    commands: Option<mpsc::Sender<(Command, mpsc::Sender<()>)>>,
+    requests: Arc<Mutex<Vec<Request>>>,
+    thread: Option<JoinHandle<()>>,
+}
+impl MockCompositor {
+    /// Start a compositor with the given seats, returning a connection to it
+    pub fn start(seats: &[&str]) -> (MockCompositor, Connection) {
+        Self::launch(seats, true)
+    }
+
+    /// Start a compositor which doesn't support the input method protocol
+    pub fn start_without_input_method(seats: &[&str]) -> (MockCompositor, Connection) {
+        Self::launch(seats, false)
+    }
+
+    fn launch(seats: &[&str], input_method: bool) -> (MockCompositor, Connection) {
+        let (server_socket, client_socket) = UnixStream::pair().unwrap();
+        let (commands, recv_commands) = mpsc::channel::<(Command, mpsc::Sender<()>)>();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let mut compositor = Compositor {
+            seats: HashMap::new(),
+            input_methods: Vec::new(),
+            popups: Vec::new(),
+            outputs: Vec::new(),
+            requests: requests.clone(),
+        };
+        let seats: Vec<String> = seats.iter().map(|name| name.to_string()).collect();
+        let thread = thread::spawn(move || {
+            let mut display = Display::<Compositor>::new().unwrap();
+            let handle = display.handle();
+            if input_method {
+                handle.create_global::<Compositor, ZwpInputMethodManagerV2, ()>(1, ());
+            }
+            handle.create_global::<Compositor, ZwpVirtualKeyboardManagerV1, ()>(1, ());
+            handle.create_global::<Compositor, WlCompositor, ()>(4, ());
+            handle.create_global::<Compositor, WlShm, ()>(1, ());
+            for name in OUTPUTS {
+                handle.create_global::<Compositor, WlOutput, String>(4, name.to_string());
+            }
+            for name in seats {
+                compositor.handle(&handle, Command::AddSeat(name));
+            }
+            display
+                .handle()
+                .insert_client(server_socket, Arc::new(ClientState))
+                .unwrap();
+
+            loop {
+                loop {
+                    match recv_commands.try_recv() {
+                        Ok((command, done)) => {
+                            compositor.handle(&handle, command);
+                            let _ = display.flush_clients();
+                            let _ = done.send(());
+                        }
+                        Err(mpsc::TryRecvError::Empty) => break,
+                        // The test is over
+                        Err(mpsc::TryRecvError::Disconnected) => return,
+                    }
+                }
+                let _ = display.dispatch_clients(&mut compositor);
+                let _ = display.flush_clients();
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+
+        let compositor = MockCompositor {
+            commands: Some(commands),
+            requests,
+            thread: Some(thread),
+        };
+        (compositor, Connection::from_socket(client_socket).unwrap())
+    }
+
+    /// Run a command, returning once its events have been sent to the client
+    fn run(&self, command: Command) {
+        let (done, wait) = mpsc::channel();
+        self.commands
+            .as_ref()
+            .unwrap()
+            .send((command, done))
+            .unwrap();
+        wait.recv_timeout(TIMEOUT).unwrap();
+    }
+
+    pub fn add_seat(&self, seat: &str) {
+        self.run(Command::AddSeat(seat.to_string()));
+    }
+
+    pub fn remove_seat(&self, seat: &str) {
+        self.run(Command::RemoveSeat(seat.to_string()));
+    }
+
+    /// Focus a text field on `seat`
+    pub fn activate(&self, seat: &str) {
+        self.run(Command::Activate(seat.to_string()));
+    }
+
+    /// Focus a text field on `seat` which already contains `text`, with the cursor at the end
+    pub fn activate_with_text(&self, seat: &str, text: &str) {
+        self.run(Command::ActivateWithText(
+            seat.to_string(),
+            text.to_string(),
+        ));
+    }
+
+    /// Unfocus the text field on `seat`
+    pub fn deactivate(&self, seat: &str) {
+        self.run(Command::Deactivate(seat.to_string()));
+    }
+
+    /// Tell the input method about the text around the cursor, which is a byte offset
+    pub fn surrounding_text(&self, seat: &str, text: &str, cursor: u32) {
+        self.run(Command::SurroundingText(
+            seat.to_string(),
+            text.to_string(),
+            cursor,
+        ));
+    }
+
+    /// Tell the input method what kind of text the focused text field expects
+    pub fn content_type(&self, seat: &str, hint: ContentHint, purpose: ContentPurpose) {
+        self.run(Command::ContentType(seat.to_string(), hint, purpose));
+    }
+
+    /// Show the input popups of `seat` on `output`, as if the focused text field were there
+    pub fn show_popup(&self, seat: &str, output: &str) {
+        self.run(Command::ShowPopup(seat.to_string(), output.to_string()));
+    }
+
+    /// Pretend another input method has taken over `seat`
+    pub fn unavailable(&self, seat: &str) {
+        self.run(Command::Unavailable(seat.to_string()));
+    }
+
+    /// Wait until the client has made `count` requests, and return them
+    pub fn wait_for_requests(&self, count: usize) -> Vec<Request> {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let requests = self.requests.lock().unwrap().clone();
+            if requests.len() >= count {
+                return requests;
+            }
+            if Instant::now() > deadline {
+                panic!("Expected {} requests, got {:?}", count, requests);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+impl Drop for MockCompositor {
+    fn drop(&mut self) {
+        // Stops the compositor, which closes the connection
+        self.commands = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
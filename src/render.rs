@@ -0,0 +1,316 @@
+//! Pictures of the keyboard, drawn without GTK or a Wayland session, so layouts can be
+//! previewed and diffed
+
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::engine::{self, KeyboardEngine};
+
+/// A key's button, positioned on the keyboard
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyBox {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+    pub label: String,
+    pub icon: Option<String>,
+    pub hint: Option<String>,
+    pub classes: Vec<String>,
+}
+
+impl KeyBox {
+    /// The text to draw when the icon can't be, showing the icon's name so the key isn't blank
+    pub fn text(&self) -> &str {
+        self.icon.as_deref().unwrap_or(&self.label)
+    }
+}
+
+/// The current page of the keyboard, laid out the way the GUI lays it out
+#[derive(Debug, Clone, PartialEq)]
+pub struct Picture {
+    pub width: i32,
+    pub height: i32,
+    pub keys: Vec<KeyBox>,
+}
+
+/// Lay out the current page for a monitor of the given size
+pub fn picture(engine: &KeyboardEngine, monitor_size: (i32, i32)) -> Picture {
+    let config = engine.config();
+    let (padding, spacing) = (config.padding, config.key_spacing);
+    // Rows are `width` wide, inside the padding
+    let width = engine.width(monitor_size.0);
+    let key_size = engine.key_size(width, engine.height(monitor_size.1), monitor_size.0);
+    let layer = engine.layer();
+
+    let mut keys = Vec::new();
+    let mut add_key = |x, y, width, name: &str| {
+        let key = engine.key(name);
+        keys.push(KeyBox {
+            x,
+            y,
+            width,
+            height: key_size.height,
+            label: engine::label(&key, layer).to_string(),
+            icon: engine::icon(&key, layer).map(str::to_string),
+            hint: key.hint.clone(),
+            classes: engine::key_classes(&key, layer, false, &[]),
+        });
+    };
+
+    let rows = engine.row_names();
+    for (i, row) in rows.iter().enumerate() {
+        let y = padding + i as i32 * (key_size.height + spacing);
+        if engine.is_split() {
+            // Halves hug the edges of the keyboard, with keys at their natural size
+            let step = key_size.width + spacing;
+            for (j, name) in row.left.iter().enumerate() {
+                add_key(padding + j as i32 * step, y, key_size.width, name);
+            }
+            let right_start = padding + width - row.right.len() as i32 * step + spacing;
+            for (j, name) in row.right.iter().enumerate() {
+                add_key(right_start + j as i32 * step, y, key_size.width, name);
+            }
+        } else {
+            // Keys stretch to fill the row
+            let count = row.left.len().max(1) as i32;
+            let key_width = (width - spacing * (count - 1)) / count;
+            for (j, name) in row.left.iter().enumerate() {
+                add_key(
+                    padding + j as i32 * (key_width + spacing),
+                    y,
+                    key_width,
+                    name,
+                );
+            }
+        }
+    }
+
+    let rows = rows.len() as i32;
+    Picture {
+        width: width + 2 * padding,
+        height: 2 * padding + rows * key_size.height + (rows - 1).max(0) * spacing,
+        keys,
+    }
+}
+
+/// Styles for the SVG, loosely following the built-in light theme
+const SVG_STYLE: &str = "
+    .background { fill: #deddda; }
+    .key rect { fill: #fafafa; stroke: #c0bfbc; }
+    .special rect { fill: #f0f0f0; }
+    .modifier-active rect { fill: #c0d7f0; }
+    .suggested-action rect { fill: #3584e4; }
+    text { font-family: sans-serif; fill: #241f31; text-anchor: middle; dominant-baseline: central; }
+    .hint { fill: #77767b; text-anchor: end; dominant-baseline: hanging; }
+";
+
+/// Draw `picture` as an SVG document
+pub fn svg(picture: &Picture) -> String {
+    let mut svg = String::new();
+    // Writing to a String can't fail
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{0}" height="{1}" viewBox="0 0 {0} {1}">"#,
+        picture.width, picture.height
+    );
+    let _ = writeln!(svg, "<style>{}</style>", SVG_STYLE);
+    let _ = writeln!(
+        svg,
+        r#"<rect class="background" width="{}" height="{}"/>"#,
+        picture.width, picture.height
+    );
+
+    for key in &picture.keys {
+        let classes = std::iter::once("key")
+            .chain(key.classes.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let _ = writeln!(
+            svg,
+            r#"<g class="{}" transform="translate({} {})">"#,
+            escape(&classes),
+            key.x,
+            key.y
+        );
+        let _ = writeln!(
+            svg,
+            r#"  <rect width="{}" height="{}" rx="6"/>"#,
+            key.width, key.height
+        );
+        let (center_x, center_y) = (key.width / 2, key.height / 2);
+        let font_size = (key.height / 3).clamp(8, 32);
+        let icon = key.icon.as_deref().and_then(find_icon);
+        match icon.and_then(|path| std::fs::read_to_string(path).ok()) {
+            Some(icon) => {
+                let size = font_size * 4 / 3;
+                let _ = writeln!(
+                    svg,
+                    r#"  <image x="{}" y="{}" width="{size}" height="{size}" href="data:image/svg+xml,{}"/>"#,
+                    center_x - size / 2,
+                    center_y - size / 2,
+                    escape(&data_url_encode(&icon)),
+                );
+            }
+            None => {
+                let _ = writeln!(
+                    svg,
+                    r#"  <text x="{}" y="{}" font-size="{}">{}</text>"#,
+                    center_x,
+                    center_y,
+                    if key.icon.is_some() {
+                        font_size / 2
+                    } else {
+                        font_size
+                    },
+                    escape(key.text())
+                );
+            }
+        }
+        if let Some(hint) = &key.hint {
+            let _ = writeln!(
+                svg,
+                r#"  <text class="hint" x="{}" y="4" font-size="{}">{}</text>"#,
+                key.width - 4,
+                font_size / 2,
+                escape(hint)
+            );
+        }
+        let _ = writeln!(svg, "</g>");
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// Find the file for a themed icon, preferring scalable versions
+pub fn find_icon(name: &str) -> Option<PathBuf> {
+    let data_dirs = std::env::var("XDG_DATA_DIRS")
+        .unwrap_or_else(|_| "/usr/local/share:/usr/share".to_string());
+    let themes = ["Adwaita", "hicolor"];
+    let sizes = ["scalable", "symbolic", "48x48", "32x32", "24x24", "16x16"];
+    data_dirs
+        .split(':')
+        .map(Path::new)
+        .flat_map(|dir| {
+            themes
+                .iter()
+                .map(move |theme| dir.join("icons").join(theme))
+        })
+        .flat_map(|theme| sizes.iter().map(move |size| theme.join(size)))
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flat_map(|contexts| contexts.flatten())
+        .flat_map(|context| {
+            ["svg", "png"].map(|extension| context.path().join(format!("{}.{}", name, extension)))
+        })
+        .find(|path| path.is_file())
+}
+
+/// Escape text for use in XML content and attributes
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Percent-encode the characters that would end or confuse a data URL
+fn data_url_encode(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '%' => "%25".to_string(),
+            '#' => "%23".to_string(),
+            '\n' | '\r' => " ".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::tests::engine;
+
+    const TEST_CONFIG: &str = "
+        width = 1000
+        height = 200
+        padding = 10
+        key_spacing = 10
+        [layouts.us]
+        default = 'test'
+        [pages.test]
+        keys = ['a b c d', 'e <space>']
+        [keys]
+        a.hint = '1'
+    ";
+
+    #[test]
+    fn layout() {
+        let picture = picture(&engine(TEST_CONFIG), (1920, 1080));
+        assert_eq!((picture.width, picture.height), (1020, 200));
+        let boxes: Vec<_> = picture
+            .keys
+            .iter()
+            .map(|key| (key.label.as_str(), key.x, key.y, key.width, key.height))
+            .collect();
+        // 1000px wide rows, with keys 85px tall
+        assert_eq!(
+            boxes,
+            vec![
+                ("a", 10, 10, 242, 85),
+                ("b", 262, 10, 242, 85),
+                ("c", 514, 10, 242, 85),
+                ("d", 766, 10, 242, 85),
+                ("e", 10, 105, 495, 85),
+                (" ", 515, 105, 495, 85),
+            ]
+        );
+        assert_eq!(picture.keys[0].hint.as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn split_layout() {
+        let picture = picture(
+            &engine(&format!("split = true\nsplit_gap = 500\n{}", TEST_CONFIG)),
+            (1920, 1080),
+        );
+        assert_eq!(picture.width, 1920);
+        let positions: Vec<_> = picture
+            .keys
+            .iter()
+            .map(|key| (key.label.as_str(), key.x, key.width))
+            .collect();
+        // Halves are 700px wide, fitting 2 keys
+        assert_eq!(
+            positions,
+            vec![
+                ("a", 10, 345),
+                ("b", 365, 345),
+                ("c", 1210, 345),
+                ("d", 1565, 345),
+                ("e", 10, 345),
+                (" ", 1565, 345),
+            ]
+        );
+        let (b, c) = (&picture.keys[1], &picture.keys[2]);
+        assert_eq!(c.x - (b.x + b.width), 500);
+        assert_eq!(picture.width - (c.x + 2 * c.width + 10), 10);
+    }
+
+    #[test]
+    fn svg_escapes_labels() {
+        let engine = engine(
+            "
+            [layouts.us]
+            default = 'test'
+            [pages.test]
+            keys = ['& <shift>']
+            ",
+        );
+        let svg = svg(&picture(&engine, (1920, 1080)));
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.contains(">&amp;</text>"));
+        assert!(svg.contains(r#"class="key special""#));
+    }
+}
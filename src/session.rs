@@ -0,0 +1,394 @@
+//! The Wayland side of dweeb, which runs on its own thread so the GUI never blocks on the
+//! compositor.
+
+use std::time::Duration;
+
+use tokio::{
+    io::unix::AsyncFd,
+    sync::mpsc::UnboundedReceiver,
+    time::{sleep_until, Instant},
+};
+use wayland_client::{backend::WaylandError, Connection};
+
+use crate::wayland::{ConnectionStatus, Error, InputAction, KeyboardWriter, WaylandEvent};
+
+/// How long to wait before reconnecting after the first failure, doubling on each attempt
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Act as an input method until the GUI exits, reconnecting to the compositor whenever the
+/// connection fails. `send_to_gui` returns false once the GUI has stopped listening.
+#[tokio::main(flavor = "current_thread")]
+pub async fn run_wayland_thread(
+    mut recv_from_gui: UnboundedReceiver<InputAction>,
+    send_to_gui: impl Fn(WaylandEvent) -> bool,
+    close_delay: Duration,
+    seat: Option<String>,
+) -> Result<(), Error> {
+    // Identifies the text field the keyboard was opened for. This keeps counting across
+    // reconnections, so the GUI never confuses a new text field with an old one.
+    let mut field = 0;
+    let mut retry_delay = MIN_RETRY_DELAY;
+
+    loop {
+        // Don't type keys which were pressed while we weren't connected
+        while recv_from_gui.try_recv().is_ok() {}
+
+        let session = match Connection::connect_to_env() {
+            Ok(conn) => {
+                run_wayland_session(
+                    conn,
+                    &mut recv_from_gui,
+                    &send_to_gui,
+                    close_delay,
+                    seat.clone(),
+                    &mut field,
+                    &mut retry_delay,
+                )
+                .await
+            }
+            Err(e) => Err(e.into()),
+        };
+        let error = match session {
+            // The GUI has exited
+            Ok(()) => return Ok(()),
+            Err(error) => error,
+        };
+        eprintln!("{}", error);
+        send_to_gui(WaylandEvent::Close);
+        if error.is_fatal() {
+            send_to_gui(WaylandEvent::Fatal(error.clone()));
+            return Err(error);
+        }
+        let status = ConnectionStatus::Retrying(error);
+        if !send_to_gui(WaylandEvent::Status(status)) {
+            return Ok(());
+        }
+
+        tokio::time::sleep(retry_delay).await;
+        retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+    }
+}
+
+/// Act as an input method on a new connection to the compositor, until the GUI exits or the
+/// connection fails.
+async fn run_wayland_session(
+    conn: Connection,
+    recv_from_gui: &mut UnboundedReceiver<InputAction>,
+    send_to_gui: &dyn Fn(WaylandEvent) -> bool,
+    close_delay: Duration,
+    seat: Option<String>,
+    field: &mut u32,
+    retry_delay: &mut Duration,
+) -> Result<(), Error> {
+    let wl_display = conn.display();
+    let mut event_queue = conn.new_event_queue();
+    let _registry = wl_display.get_registry(&event_queue.handle(), ());
+    let mut writer = KeyboardWriter::new(&mut event_queue, seat)?;
+    if writer.is_unavailable() {
+        return Err(Error::Unavailable);
+    }
+
+    // We're up and running, so start over if we have to reconnect later
+    *retry_delay = MIN_RETRY_DELAY;
+    if !send_to_gui(WaylandEvent::Status(ConnectionStatus::Connected)) {
+        return Ok(());
+    }
+
+    let mut was_active = writer.is_active();
+    let mut last_activation = writer.activation_serial();
+    // The focused text field as it was last seen, to recognize it if it's focused again
+    let mut last_text_field = writer.text_field();
+    // The output the focused text field was last seen on
+    let mut last_output = writer.focused_output();
+    // Whether the keyboard has been closed since it was last opened
+    let mut closed = true;
+    // When to close the keyboard, once no text field is focused
+    let mut close_at: Option<Instant> = None;
+
+    loop {
+        if writer.is_unavailable() {
+            return Err(Error::Unavailable);
+        }
+
+        let is_active = writer.is_active();
+        let activation = writer.activation_serial();
+        let text_field = writer.text_field();
+        if is_active && activation != last_activation {
+            last_activation = activation;
+            // Being reactivated before the close debounce ran out is most likely the
+            // same text field flickering, unless the compositor describes a different one.
+            if closed || was_active || text_field != last_text_field {
+                *field += 1;
+            }
+            // Immediately open the keyboard
+            if !send_to_gui(WaylandEvent::Open(*field, text_field.clone())) {
+                return Ok(());
+            }
+            closed = false;
+        } else if is_active && text_field != last_text_field {
+            // E.g. the text changed, which can affect whether the next letter is capitalized
+            if !send_to_gui(WaylandEvent::TextField(text_field.clone())) {
+                return Ok(());
+            }
+        }
+        let output = writer.focused_output();
+        if output != last_output {
+            // Only known outputs are worth moving the keyboard to
+            if let Some(name) = &output {
+                if !send_to_gui(WaylandEvent::Output(name.clone())) {
+                    return Ok(());
+                }
+            }
+            last_output = output;
+        }
+        if is_active {
+            close_at = None;
+            last_text_field = text_field;
+        } else if was_active {
+            close_at = Some(Instant::now() + close_delay);
+        }
+        was_active = is_active;
+
+        let Some(read_guard) = event_queue.prepare_read() else {
+            // Some events were queued without being dispatched
+            event_queue.dispatch_pending(&mut writer)?;
+            continue;
+        };
+        let fd = read_guard.connection_fd();
+        let async_fd = AsyncFd::new(fd)?;
+
+        tokio::select! {
+            action = recv_from_gui.recv() => {
+                match action {
+                    Some(action) => writer.send(action),
+                    // Receiver is dead -- all senders are dropped.
+                    None => return Ok(()),
+                }
+            },
+            async_guard = async_fd.readable() => {
+                async_guard?.clear_ready();
+                // Drop the async_fd since it's holding a reference to the read_guard,
+                // which is dropped on read. We don't need to read from it anyways.
+                std::mem::drop(async_fd);
+                // This should not block because we already ensured readiness
+                let event = read_guard.read();
+                match event {
+                    // There are events but another thread processed them, we don't need to dispatch
+                    Ok(0) => {}
+                    // We have some events
+                    Ok(_) => {
+                        event_queue.dispatch_pending(&mut writer)?;
+                    }
+                    // No events to receive
+                    Err(WaylandError::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                    // The compositor has gone away
+                    Err(e) => return Err(e.into()),
+                }
+            },
+            // Debounce closing the keyboard.
+            // This prevents the keyboard from flashing in and out epileptically when switching
+            // between inputs in some implementations (e.g. hyprland).
+            _ = sleep_until(close_at.unwrap_or_else(Instant::now)), if close_at.is_some() => {
+                close_at = None;
+                if !closed {
+                    if !send_to_gui(WaylandEvent::Close) {
+                        return Ok(());
+                    }
+                    closed = true;
+                }
+            },
+        }
+
+        // Send any new messages to the socket.
+        event_queue.flush()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_compositor::{MockCompositor, Request};
+    use std::{
+        sync::mpsc,
+        thread::{self, JoinHandle},
+    };
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+    const CLOSE_DELAY: Duration = Duration::from_millis(200);
+    /// Long enough that the test always refocuses before it runs out, however slow the machine
+    const LONG_CLOSE_DELAY: Duration = Duration::from_secs(60);
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// A session running on its own thread, seen from the GUI's side
+    struct Session {
+        send_input: Option<UnboundedSender<InputAction>>,
+        events: mpsc::Receiver<WaylandEvent>,
+        thread: JoinHandle<Result<(), Error>>,
+    }
+    impl Session {
+        fn start(conn: Connection, close_delay: Duration) -> Session {
+            let (send_event, events) = mpsc::channel();
+            let (send_input, mut recv_from_gui) = unbounded_channel();
+
+            let thread = thread::spawn(move || {
+                let mut retry_delay = MIN_RETRY_DELAY;
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .unwrap();
+                runtime.block_on(run_wayland_session(
+                    conn,
+                    &mut recv_from_gui,
+                    &move |event| send_event.send(event).is_ok(),
+                    close_delay,
+                    None,
+                    &mut 0,
+                    &mut retry_delay,
+                ))
+            });
+            Session {
+                send_input: Some(send_input),
+                events,
+                thread,
+            }
+        }
+
+        fn next_event(&self) -> WaylandEvent {
+            self.events
+                .recv_timeout(TIMEOUT)
+                .expect("No event received")
+        }
+
+        fn expect_open(&self) -> u32 {
+            match self.next_event() {
+                WaylandEvent::Open(field, _) => field,
+                event => panic!("Expected Open, got {:?}", event),
+            }
+        }
+
+        fn send(&self, action: InputAction) {
+            self.send_input.as_ref().unwrap().send(action).unwrap();
+        }
+
+        /// Wait for the session to end by itself
+        fn join(self) -> Result<(), Error> {
+            let Session {
+                send_input, thread, ..
+            } = self;
+            let result = thread.join().unwrap();
+            drop(send_input);
+            result
+        }
+
+        /// Close the GUI's end of the channel, which ends the session
+        fn exit(mut self) -> Result<(), Error> {
+            self.send_input = None;
+            self.join()
+        }
+    }
+
+    fn start() -> (MockCompositor, Session) {
+        start_with_close_delay(CLOSE_DELAY)
+    }
+
+    fn start_with_close_delay(close_delay: Duration) -> (MockCompositor, Session) {
+        let (compositor, conn) = MockCompositor::start(&["seat0"]);
+        let session = Session::start(conn, close_delay);
+        assert!(matches!(
+            session.next_event(),
+            WaylandEvent::Status(ConnectionStatus::Connected)
+        ));
+        (compositor, session)
+    }
+
+    #[test]
+    fn opens_and_closes() {
+        let (compositor, session) = start();
+
+        compositor.activate("seat0");
+        assert_eq!(session.expect_open(), 1);
+        compositor.deactivate("seat0");
+        assert!(matches!(session.next_event(), WaylandEvent::Close));
+        compositor.activate("seat0");
+        assert_eq!(session.expect_open(), 2);
+
+        assert_eq!(session.exit(), Ok(()));
+    }
+
+    #[test]
+    fn follows_focused_output() {
+        let (compositor, session) = start();
+
+        compositor.activate("seat0");
+        session.expect_open();
+        compositor.show_popup("seat0", "DP-1");
+        assert!(matches!(session.next_event(), WaylandEvent::Output(name) if name == "DP-1"));
+
+        assert_eq!(session.exit(), Ok(()));
+    }
+
+    #[test]
+    fn refocus_within_close_delay() {
+        let (compositor, session) = start_with_close_delay(LONG_CLOSE_DELAY);
+
+        compositor.activate("seat0");
+        assert_eq!(session.expect_open(), 1);
+        compositor.deactivate("seat0");
+        // Give the session a moment to see the text field unfocus, which it would otherwise see
+        // together with the refocus
+        thread::sleep(CLOSE_DELAY);
+        compositor.activate("seat0");
+        // The keyboard never closed, and it's considered the same text field
+        assert_eq!(session.expect_open(), 1);
+
+        assert_eq!(session.exit(), Ok(()));
+    }
+
+    #[test]
+    fn other_field_within_close_delay() {
+        let (compositor, session) = start_with_close_delay(LONG_CLOSE_DELAY);
+
+        compositor.activate_with_text("seat0", "Dear Sir");
+        assert_eq!(session.expect_open(), 1);
+        compositor.deactivate("seat0");
+        thread::sleep(CLOSE_DELAY);
+        compositor.activate_with_text("seat0", "Re: hello");
+        // The text is different, so it's a different text field
+        assert_eq!(session.expect_open(), 2);
+
+        assert_eq!(session.exit(), Ok(()));
+    }
+
+    #[test]
+    fn types_keys() {
+        let (compositor, session) = start();
+
+        compositor.activate("seat0");
+        session.expect_open();
+        session.send(InputAction::Commit("h".to_string()));
+        assert_eq!(
+            compositor.wait_for_requests(1)[0],
+            Request::CommitString {
+                seat: "seat0".to_string(),
+                text: "h".to_string()
+            }
+        );
+
+        assert_eq!(session.exit(), Ok(()));
+    }
+
+    #[test]
+    fn compositor_goes_away() {
+        let (compositor, session) = start();
+        drop(compositor);
+        assert!(matches!(session.join(), Err(Error::Disconnected(_))));
+    }
+
+    #[test]
+    fn input_method_unavailable() {
+        let (compositor, session) = start();
+        compositor.unavailable("seat0");
+        assert_eq!(session.join(), Err(Error::Unavailable));
+    }
+}
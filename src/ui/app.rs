@@ -1,50 +1,122 @@
-use std::time::{Duration, Instant};
+use std::{cell::RefCell, ffi::OsStr, rc::Rc, time::Instant};
 
-use dirs::config_dir;
 use gtk::{
-    gdk::Display,
+    gdk, gio,
     glib::{clone, Receiver},
     prelude::*,
-    CssProvider,
 };
 
 use relm4::{factory::FactoryVecDeque, ComponentParts, ComponentSender, SimpleComponent};
 use tokio::sync::mpsc::UnboundedSender;
 
-use crate::config::{self, KeyConfig};
+use dweeb::{
+    config::{self, Anchor, KeyConfig, Orientation, OutputSelection, ShellLayer},
+    engine::{self, Action, KeySize, KeyboardEngine, Swipe},
+    wayland::{self, ConnectionStatus, InputAction, TextField, WaylandEvent},
+};
+
+use crate::ipc::IpcCommand;
 
-use super::row::{Row, RowInput};
+use super::{
+    floating::{self, Placement},
+    monitor::{current_monitor, select_monitor, watch_monitors},
+    row::{Row, RowInput},
+    theme::Theme,
+};
 
 #[derive(Debug)]
 pub enum AppInput {
-    Open,
+    /// A text field was focused. Text fields are identified by the activation they were
+    /// focused in.
+    Open(u32, TextField),
+    /// The focused text field's text or content type changed
+    TextField(TextField),
+    /// The focused text field is on the output with this connector name
+    FocusedOutput(String),
     Close,
-    KeyPress(String),
-}
-
-/// Represents the keyboard's shift/capslock state
-#[derive(PartialEq, Debug, Clone)]
-pub enum Layer {
-    Normal,
-    Shifted,
-    Locked,
+    /// Whether dweeb can currently type into text fields
+    Status(ConnectionStatus),
+    /// The Wayland thread stopped for good, so there's no point in keeping the keyboard up
+    Fatal(wayland::Error),
+    /// The keyboard finished sliding out of view
+    Hidden,
+    /// Hide the keyboard until a different text field is focused
+    Dismiss,
+    /// A key was pushed down
+    KeyDown(KeyConfig),
+    KeyPress(KeyConfig),
+    /// A key was held down
+    KeyHold(KeyConfig),
+    /// A finger or mouse button went down (true) or up (false) anywhere on the keyboard
+    Pointer(bool),
+    /// The keyboard moved to another monitor, or its monitor changed size
+    MonitorChanged(gdk::Monitor),
+    /// The floating keyboard's handle was dragged by (x, y) since the last move
+    FloatingMove(f64, f64),
+    /// The floating keyboard's handle was let go
+    FloatingMoveEnd,
+    /// The floating keyboard's resize grip was dragged by (x, y)
+    FloatingResize(f64, f64),
+    /// Move the keyboard to another output
+    SetOutput(OutputSelection),
+    /// Attach the keyboard to another screen edge
+    SetAnchor(Anchor),
+    SetShellLayer(ShellLayer),
+    /// Whether to push other windows out of the way
+    SetExclusive(bool),
 }
 
 pub struct AppModel {
+    status: ConnectionStatus,
+    /// Whether the keyboard is shown, or sliding into view
     is_open: bool,
-    current_page: &'static str,
-    current_layer: Layer,
-    last_layer_change: Instant,
-    send_key: UnboundedSender<String>,
+    /// Whether the window is mapped, which lasts until the keyboard has slid out of view
+    is_visible: bool,
+    /// The text field the keyboard was last opened for
+    field: u32,
+    /// A text field the keyboard was hidden in, so it shouldn't reopen for it
+    dismissed_field: Option<u32>,
+    send_input: UnboundedSender<InputAction>,
     rows: FactoryVecDeque<Row>,
-    config: config::Config,
+    /// Logical size of the monitor the keyboard is shown on
+    monitor_size: (i32, i32),
+    /// Connector name of the monitor the keyboard is shown on
+    output: Option<String>,
+    /// Connector name of the monitor with the focused text field, once the compositor has said
+    focused_output: Option<String>,
+    /// Position and size of the keyboard, when it's floating rather than docked
+    floating: Option<Placement>,
+    /// Where the error that stopped the Wayland thread is left for main to report
+    fatal_error: Rc<RefCell<Option<wayland::Error>>>,
+    /// How many fingers or mouse buttons are down on the keyboard
+    pointers_down: u32,
+    window: gtk::Window,
+    engine: KeyboardEngine,
 }
 
+/// Used to size the keyboard until the monitor is known
+const DEFAULT_MONITOR_SIZE: (i32, i32) = (1920, 1080);
+
+/// Identifies the notification shown when text input isn't working
+const STATUS_NOTIFICATION: &str = "status";
+
+/// Height of the row used to drag the floating keyboard around
+const HANDLE_HEIGHT: i32 = 24;
+
+/// The smallest size the floating keyboard can be resized to
+const MIN_FLOATING_SIZE: (i32, i32) = (200, 100);
+
 #[relm4::component(pub)]
 impl SimpleComponent for AppModel {
     type Input = AppInput;
     type Output = ();
-    type Init = (UnboundedSender<String>, Receiver<bool>, config::Config);
+    type Init = (
+        UnboundedSender<InputAction>,
+        Receiver<WaylandEvent>,
+        Receiver<IpcCommand>,
+        config::Config,
+        Rc<RefCell<Option<wayland::Error>>>,
+    );
 
     view! {
         gtk::Window {
@@ -52,58 +124,207 @@ impl SimpleComponent for AppModel {
             set_default_width: 300,
             set_default_height: 100,
             #[watch]
-            set_visible: model.is_open,
-
-            gtk::Box {
-                set_orientation: gtk::Orientation::Vertical,
-                set_margin_top: model.config.padding,
-                set_margin_start: model.config.padding,
-                set_margin_end: model.config.padding,
-                set_margin_bottom: model.config.padding,
-                set_width_request: model.config.width,
-                set_halign: gtk::Align::Center,
-
-                #[local_ref]
-                rows_container -> gtk::Box {
+            set_visible: model.is_visible,
+            #[watch]
+            set_css_classes: &model.css_classes().iter().map(String::as_str).collect::<Vec<_>>(),
+
+            // Swipe down across the keyboard to hide it. Swipes starting on a key are handled by
+            // the key, since it claims them.
+            add_controller = gtk::GestureDrag {
+                set_propagation_phase: gtk::PropagationPhase::Capture,
+                connect_drag_update[sender] => move |gesture, x, y| {
+                    // set_state only succeeds the first time, so this is sent once per swipe
+                    if engine::swipe(x, y) == Some(Swipe::Dismiss)
+                        && gesture.set_state(gtk::EventSequenceState::Claimed)
+                    {
+                        sender.input(AppInput::Dismiss);
+                    }
+                },
+            },
+
+            // Keys are rebuilt when the page changes, so releases are tracked here instead
+            add_controller = gtk::EventControllerLegacy {
+                set_propagation_phase: gtk::PropagationPhase::Capture,
+                connect_event[sender] => move |_, event| {
+                    match event.event_type() {
+                        gdk::EventType::ButtonPress | gdk::EventType::TouchBegin => {
+                            sender.input(AppInput::Pointer(true))
+                        }
+                        gdk::EventType::ButtonRelease
+                        | gdk::EventType::TouchEnd
+                        | gdk::EventType::TouchCancel => sender.input(AppInput::Pointer(false)),
+                        _ => {}
+                    }
+                    gtk::Inhibit(false)
+                },
+            },
+
+            gtk::Revealer {
+                #[watch]
+                set_transition_type: match model.config().anchor {
+                    Anchor::Top => gtk::RevealerTransitionType::SlideDown,
+                    Anchor::Bottom => gtk::RevealerTransitionType::SlideUp,
+                },
+                set_transition_duration: model.config().animation_duration,
+                #[watch]
+                set_reveal_child: model.is_open,
+                connect_child_revealed_notify[sender] => move |revealer| {
+                    if !revealer.is_child_revealed() {
+                        sender.input(AppInput::Hidden);
+                    }
+                },
+
+                #[wrap(Some)]
+                set_child = &gtk::Box {
                     set_orientation: gtk::Orientation::Vertical,
-                    set_spacing: model.config.key_spacing,
-                }
+                    set_margin_top: model.config().padding,
+                    set_margin_start: model.config().padding,
+                    set_margin_end: model.config().padding,
+                    set_margin_bottom: model.config().padding,
+                    #[watch]
+                    set_width_request: model.width(),
+                    #[watch]
+                    set_halign: if model.engine.is_split() { gtk::Align::Fill } else { gtk::Align::Center },
+
+                    gtk::Label {
+                        set_css_classes: &["status-banner"],
+                        set_wrap: true,
+                        #[watch]
+                        set_visible: model.status != ConnectionStatus::Connected,
+                        #[watch]
+                        set_label: &model.status.to_string(),
+                    },
+
+                    gtk::Box {
+                        set_css_classes: &["handle"],
+                        set_height_request: HANDLE_HEIGHT,
+                        #[watch]
+                        set_visible: model.floating.is_some(),
+
+                        add_controller = gtk::GestureDrag {
+                            connect_drag_update[sender] => move |_, x, y| {
+                                sender.input(AppInput::FloatingMove(x, y));
+                            },
+                            connect_drag_end[sender] => move |_, _, _| {
+                                sender.input(AppInput::FloatingMoveEnd);
+                            },
+                        },
+
+                        gtk::Image {
+                            set_icon_name: Some("list-drag-handle-symbolic"),
+                            set_hexpand: true,
+                        },
+                        gtk::Image {
+                            set_css_classes: &["grip"],
+                            set_icon_name: Some("view-fullscreen-symbolic"),
+
+                            add_controller = gtk::GestureDrag {
+                                connect_drag_begin => move |gesture, _, _| {
+                                    // Stop the handle from moving the keyboard at the same time
+                                    gesture.set_state(gtk::EventSequenceState::Claimed);
+                                },
+                                connect_drag_end[sender] => move |_, x, y| {
+                                    sender.input(AppInput::FloatingResize(x, y));
+                                },
+                            },
+                        },
+                    },
+
+                    #[local_ref]
+                    rows_container -> gtk::Box {
+                        set_orientation: gtk::Orientation::Vertical,
+                        set_spacing: model.config().key_spacing,
+                    }
+                },
             }
         }
     }
 
     fn init(
-        (send_key, recv_from_wl, config): Self::Init,
+        (send_input, recv_from_wl, recv_from_ipc, config, fatal_error): Self::Init,
         window: &Self::Root,
         sender: ComponentSender<Self>,
     ) -> relm4::ComponentParts<Self> {
         let rows = FactoryVecDeque::new(gtk::Box::default(), sender.input_sender());
 
-        AppModel::load_css();
+        Theme::load(&config.theme, &config.light_theme, &config.dark_theme);
+        let monitor = current_monitor(window);
         let mut model = AppModel {
-            current_page: "uninitialized",
-            current_layer: Layer::Normal,
-            last_layer_change: Instant::now(),
+            status: ConnectionStatus::Connected,
             is_open: false,
-            send_key,
+            is_visible: false,
+            field: 0,
+            dismissed_field: None,
+            send_input,
             rows,
-            config,
+            monitor_size: monitor
+                .as_ref()
+                .map(monitor_size)
+                .unwrap_or(DEFAULT_MONITOR_SIZE),
+            output: monitor
+                .as_ref()
+                .and_then(|monitor| monitor.connector())
+                .map(Into::into),
+            focused_output: None,
+            floating: None,
+            fatal_error,
+            pointers_down: 0,
+            window: window.clone(),
+            engine: KeyboardEngine::new(config),
         };
+        model.engine.set_orientation(Orientation::from_size(
+            model.monitor_size.0,
+            model.monitor_size.1,
+        ));
+        if model.config().floating {
+            model.floating = Some(model.saved_placement());
+        }
 
-        model.set_page("default");
+        model.render_page();
 
-        configure_layer_shell(&window);
+        gtk4_layer_shell::init_for_window(window);
+        model.configure_layer_shell();
+        if !select_monitor(window, &model.config().output, None) {
+            eprintln!(
+                "Output {:?} not found, letting the compositor choose",
+                model.config().output
+            );
+        }
+        watch_monitors(
+            window,
+            clone!(@strong sender => move |monitor| {
+                sender.input(AppInput::MonitorChanged(monitor.clone()));
+            }),
+        );
 
         let rows_container = model.rows.widget();
         let widgets = view_output!();
 
         recv_from_wl.attach(
             None,
-            clone!(@strong window, @strong sender => move |should_be_open| {
-                if should_be_open {
-                    sender.input(AppInput::Open);
-                } else {
-                    sender.input(AppInput::Close);
+            clone!(@strong sender => move |event| {
+                sender.input(match event {
+                    WaylandEvent::Open(field, text_field) => AppInput::Open(field, text_field),
+                    WaylandEvent::TextField(text_field) => AppInput::TextField(text_field),
+                    WaylandEvent::Output(name) => AppInput::FocusedOutput(name),
+                    WaylandEvent::Close => AppInput::Close,
+                    WaylandEvent::Status(status) => AppInput::Status(status),
+                    WaylandEvent::Fatal(error) => AppInput::Fatal(error),
+                });
+                Continue(true)
+            }),
+        );
+
+        recv_from_ipc.attach(
+            None,
+            clone!(@strong sender => move |command| {
+                match command {
+                    IpcCommand::Output(selection) => sender.input(AppInput::SetOutput(selection)),
+                    IpcCommand::Anchor(anchor) => sender.input(AppInput::SetAnchor(anchor)),
+                    IpcCommand::Layer(layer) => sender.input(AppInput::SetShellLayer(layer)),
+                    IpcCommand::Exclusive(exclusive) => {
+                        sender.input(AppInput::SetExclusive(exclusive))
+                    }
                 }
                 Continue(true)
             }),
@@ -114,107 +335,372 @@ impl SimpleComponent for AppModel {
 
     fn update(&mut self, msg: Self::Input, _sender: ComponentSender<Self>) {
         match msg {
-            AppInput::Close => self.is_open = false,
-            AppInput::Open => self.is_open = true,
-
-            AppInput::KeyPress(key) => match key.as_str() {
-                "<shift>" => self.set_layer(match self.current_layer {
-                    Layer::Normal => Layer::Shifted,
-                    Layer::Shifted => {
-                        if self.last_layer_change.elapsed() < Duration::from_millis(500) {
-                            Layer::Locked
-                        } else {
-                            Layer::Normal
-                        }
-                    }
-                    Layer::Locked => Layer::Normal,
-                }),
-                "<symbols>" => self.set_page("symbols"),
-                "<default>" => self.set_page("default"),
-                key => {
-                    self.send_key.send(key.to_string()).unwrap();
-                    if self.current_layer == Layer::Shifted {
-                        self.set_layer(Layer::Normal);
-                    }
+            AppInput::Close => self.close(),
+            AppInput::Hidden => {
+                if !self.is_open {
+                    self.is_visible = false;
                 }
-            },
+            }
+            AppInput::Status(status) => {
+                if status == self.status {
+                    return;
+                }
+                let app = relm4::main_application();
+                if status == ConnectionStatus::Connected {
+                    app.withdraw_notification(STATUS_NOTIFICATION);
+                } else {
+                    let notification = gio::Notification::new("dweeb");
+                    notification.set_body(Some(&status.to_string()));
+                    app.send_notification(Some(STATUS_NOTIFICATION), &notification);
+                }
+                self.status = status;
+            }
+            AppInput::Fatal(error) => {
+                let notification = gio::Notification::new("dweeb");
+                notification.set_body(Some(&error.to_string()));
+                let app = relm4::main_application();
+                app.send_notification(Some(STATUS_NOTIFICATION), &notification);
+                self.fatal_error.replace(Some(error));
+                app.quit();
+            }
+            AppInput::Open(field, text_field) => {
+                self.field = field;
+                if self.dismissed_field != Some(field) {
+                    self.dismissed_field = None;
+                    self.is_open = true;
+                    self.is_visible = true;
+                }
+                let actions = self.engine.set_text_field(&text_field);
+                self.run_actions(actions);
+                self.follow_focused_output();
+            }
+            AppInput::TextField(text_field) => {
+                let actions = self.engine.set_text_field(&text_field);
+                self.run_actions(actions);
+            }
+            AppInput::FocusedOutput(name) => {
+                self.focused_output = Some(name);
+                self.follow_focused_output();
+            }
+            AppInput::Dismiss => self.dismiss(),
+            AppInput::MonitorChanged(monitor) => {
+                // A pinned output may have just been connected
+                select_monitor(
+                    &self.window,
+                    &self.config().output,
+                    self.focused_output.as_deref(),
+                );
+                self.set_monitor(&monitor);
+            }
+            AppInput::SetOutput(selection) => {
+                if !select_monitor(&self.window, &selection, self.focused_output.as_deref()) {
+                    eprintln!(
+                        "Output {:?} not found, letting the compositor choose",
+                        selection
+                    );
+                }
+                self.engine.config_mut().output = selection;
+                if let Some(monitor) = current_monitor(&self.window) {
+                    self.set_monitor(&monitor);
+                }
+            }
+            AppInput::SetAnchor(anchor) => {
+                self.engine.config_mut().anchor = anchor;
+                self.configure_layer_shell();
+            }
+            AppInput::SetShellLayer(layer) => {
+                self.engine.config_mut().shell_layer = layer;
+                self.configure_layer_shell();
+            }
+            AppInput::SetExclusive(exclusive) => {
+                self.engine.config_mut().exclusive = exclusive;
+                self.configure_layer_shell();
+            }
+            AppInput::FloatingMove(x, y) => {
+                let anchor = self.config().anchor;
+                if let Some(placement) = self.floating.as_mut() {
+                    // The offset is relative to where the drag started on the handle, which
+                    // moves along with the keyboard, so each offset is a step from the last one.
+                    placement.x += x.round() as i32;
+                    placement.y += match anchor {
+                        Anchor::Top => y.round() as i32,
+                        Anchor::Bottom => -y.round() as i32,
+                    };
+                    self.apply_placement();
+                }
+            }
+            AppInput::FloatingMoveEnd => self.save_placement(),
+            AppInput::FloatingResize(x, y) => {
+                let (width, height) = (self.width(), self.height());
+                let anchor = self.config().anchor;
+                if let Some(placement) = self.floating.as_mut() {
+                    placement.width = Some((width + x.round() as i32).max(MIN_FLOATING_SIZE.0));
+                    // Dragging away from the anchored edge makes the keyboard taller
+                    let y = match anchor {
+                        Anchor::Top => y.round() as i32,
+                        Anchor::Bottom => -y.round() as i32,
+                    };
+                    placement.height = Some((height + y).max(MIN_FLOATING_SIZE.1));
+                    self.save_placement();
+                    self.render_page();
+                }
+            }
+
+            AppInput::Pointer(true) => self.pointers_down += 1,
+            AppInput::Pointer(false) => {
+                self.pointers_down = self.pointers_down.saturating_sub(1);
+                if self.pointers_down == 0 {
+                    let actions = self.engine.release(Instant::now());
+                    self.run_actions(actions);
+                }
+            }
+            AppInput::KeyDown(key) => {
+                let actions = self.engine.key_down(&key, Instant::now());
+                self.run_actions(actions);
+            }
+            AppInput::KeyPress(key) => {
+                let actions = self.engine.tap(&key, Instant::now());
+                self.run_actions(actions);
+            }
+            AppInput::KeyHold(key) => {
+                let actions = self.engine.hold(&key, Instant::now());
+                self.run_actions(actions);
+            }
         }
     }
 }
 
 impl AppModel {
-    fn load_css() {
-        // Load the CSS file and add it to the provider
-        let provider = CssProvider::new();
-        provider.load_from_data(include_str!("style.css"));
+    fn config(&self) -> &config::Config {
+        self.engine.config()
+    }
 
-        if let Some(path) = config_dir() {
-            if let Ok(user_css) = std::fs::read_to_string(path.join("dweeb/style.css")) {
-                provider.load_from_data(&user_css);
+    /// Do what the engine asks for after a key press
+    fn run_actions(&mut self, actions: Vec<Action>) {
+        for action in actions {
+            match action {
+                Action::Input(input) => self.send_input(input),
+                Action::Render => self.render_page(),
+                Action::SetLayer(layer) => self.rows.guard().broadcast(RowInput::Shift(layer)),
+                Action::Dismiss => self.dismiss(),
+                Action::Spawn(command) => spawn(&command),
             }
         }
+    }
 
-        // Add the provider to the default screen
-        gtk::style_context_add_provider_for_display(
-            &Display::default().expect("Could not connect to a display."),
-            &provider,
-            gtk::STYLE_PROVIDER_PRIORITY_APPLICATION,
-        );
+    fn send_input(&self, action: InputAction) {
+        if let Err(e) = self.send_input.send(action) {
+            eprintln!("Could not send {:?}: the Wayland thread has stopped", e.0);
+        }
     }
 
-    fn set_page(&mut self, page: &'static str) {
-        if page == self.current_page {
-            return;
+    /// Hide the keyboard until a different text field is focused
+    fn dismiss(&mut self) {
+        self.close();
+        self.dismissed_field = Some(self.field);
+    }
+
+    fn close(&mut self) {
+        self.is_open = false;
+        // Otherwise the window is hidden once the revealer has finished sliding out
+        if self.config().animation_duration == 0 {
+            self.is_visible = false;
         }
-        self.current_page = page;
+    }
 
-        let layout_name = &self.config.layout;
-        let layout = &self.config.layouts[layout_name];
-        let page = &self.config.pages[&layout[self.current_page]];
+    /// (Re)build the rows of keys for the current page
+    fn render_page(&mut self) {
+        let key_size = self.key_size();
+        let layer = self.engine.layer().clone();
+        let spacing = self.config().key_spacing;
+        let page_classes = self.engine.page_classes();
 
+        let page_rows = self.engine.rows();
         let mut rows = self.rows.guard();
         rows.clear();
-        for row in &page.keys {
-            let child_key_configs = row
-                .split(' ')
-                .map(|s| {
-                    (
-                        match self.config.keys.get(s) {
-                            Some(config) => config.clone(),
-                            // Provide a default config for simple keys
-                            None => KeyConfig::new(s),
-                        },
-                        self.current_layer.clone(),
-                    )
-                })
-                .collect();
-            rows.push_back((child_key_configs, self.config.key_spacing));
+        for row in page_rows {
+            rows.push_back((
+                row.left,
+                row.right,
+                layer.clone(),
+                spacing,
+                key_size,
+                page_classes.clone(),
+            ));
+        }
+    }
+
+    /// The keyboard's width, in pixels
+    fn width(&self) -> i32 {
+        match self.floating.and_then(|placement| placement.width) {
+            Some(width) => width,
+            None => self.engine.width(self.monitor_size.0),
         }
     }
 
-    fn set_layer(&mut self, layer: Layer) {
-        self.current_layer = layer;
-        self.last_layer_change = Instant::now();
+    /// Fit the keys of the current page into the keyboard
+    fn key_size(&self) -> KeySize {
+        self.engine
+            .key_size(self.width(), self.height(), self.monitor_size.0)
+    }
+
+    /// Move the keyboard to the output with the focused text field, if it's set to follow it
+    fn follow_focused_output(&mut self) {
+        if self.config().output != OutputSelection::Focused {
+            return;
+        }
+        select_monitor(
+            &self.window,
+            &OutputSelection::Focused,
+            self.focused_output.as_deref(),
+        );
+        if let Some(monitor) = current_monitor(&self.window) {
+            self.set_monitor(&monitor);
+        }
+    }
 
-        self.rows
-            .guard()
-            .broadcast(RowInput::Shift(self.current_layer.clone()))
+    /// Update the keyboard's size and placement for the monitor it's shown on
+    fn set_monitor(&mut self, monitor: &gdk::Monitor) {
+        let size = monitor_size(monitor);
+        let output = monitor.connector().map(Into::into);
+        self.engine
+            .set_orientation(Orientation::from_size(size.0, size.1));
+        if self.output != output {
+            self.output = output;
+            self.monitor_size = size;
+            if self.floating.is_some() {
+                self.floating = Some(self.saved_placement());
+                self.apply_placement();
+            }
+            self.render_page();
+        } else if self.monitor_size != size {
+            self.monitor_size = size;
+            self.render_page();
+        }
     }
+
+    /// The keyboard's height, in pixels
+    fn height(&self) -> i32 {
+        match self.floating.and_then(|placement| placement.height) {
+            Some(height) => height,
+            None => self.engine.height(self.monitor_size.1),
+        }
+    }
+
+    /// The floating placement last used on the current output, or a default one centered at
+    /// the bottom.
+    fn saved_placement(&self) -> Placement {
+        self.output
+            .as_deref()
+            .and_then(floating::load)
+            .unwrap_or_else(|| Placement {
+                x: (self.monitor_size.0 - self.config().width.resolve(self.monitor_size.0)) / 2,
+                y: self.config().padding,
+                width: None,
+                height: None,
+            })
+    }
+
+    fn save_placement(&self) {
+        if let (Some(output), Some(placement)) = (&self.output, self.floating) {
+            floating::save(output, placement);
+        }
+    }
+
+    /// Move the floating keyboard to its placement, keeping it on screen
+    fn apply_placement(&mut self) {
+        let (edge, opposite) = anchor_edges(self.config().anchor);
+        let Some(placement) = self.floating.as_mut() else {
+            return;
+        };
+        let max_x = self.monitor_size.0 - self.window.width();
+        let max_y = self.monitor_size.1 - self.window.height();
+        placement.x = placement.x.min(max_x).max(0);
+        placement.y = placement.y.min(max_y).max(0);
+
+        gtk4_layer_shell::set_margin(&self.window, gtk4_layer_shell::Edge::Left, placement.x);
+        gtk4_layer_shell::set_margin(&self.window, edge, placement.y);
+        gtk4_layer_shell::set_margin(&self.window, opposite, 0);
+    }
+
+    /// Apply the configured layer, anchoring and exclusive zone to the layer surface
+    fn configure_layer_shell(&mut self) {
+        let window = &self.window;
+        let floating = self.floating.is_some();
+        gtk4_layer_shell::set_layer(
+            window,
+            match self.config().shell_layer {
+                ShellLayer::Top => gtk4_layer_shell::Layer::Top,
+                ShellLayer::Overlay => gtk4_layer_shell::Layer::Overlay,
+            },
+        );
+        if self.config().exclusive && !floating {
+            // Push other windows out of the way
+            gtk4_layer_shell::auto_exclusive_zone_enable(window);
+        } else {
+            gtk4_layer_shell::set_exclusive_zone(window, 0);
+        }
+        // A floating keyboard is anchored to a corner, and positioned with margins
+        let (edge, opposite) = anchor_edges(self.config().anchor);
+        let anchors = [
+            (gtk4_layer_shell::Edge::Left, true),
+            (gtk4_layer_shell::Edge::Right, !floating),
+            (edge, true),
+            (opposite, false),
+        ];
+
+        for (anchor, state) in anchors {
+            gtk4_layer_shell::set_anchor(window, anchor, state);
+        }
+        self.apply_placement();
+    }
+
+    /// CSS classes for the window, so themes can style the keyboard by page, layout and layer
+    fn css_classes(&self) -> Vec<String> {
+        // GTK windows carry this class by default, which set_css_classes would otherwise remove
+        let mut classes = vec!["background".to_string()];
+        classes.extend(self.engine.page_classes());
+        classes.push(
+            match self.engine.orientation() {
+                Orientation::Portrait => "portrait",
+                Orientation::Landscape => "landscape",
+            }
+            .into(),
+        );
+        if self.engine.is_split() {
+            classes.push("split".into());
+        }
+        if self.floating.is_some() {
+            classes.push("floating".into());
+        }
+        if let Some(class) = self.engine.layer().css_class() {
+            classes.push(class.into());
+        }
+        classes
+    }
+}
+
+/// Run a program in the background
+fn spawn(command: &[String]) {
+    let Some(program) = command.first() else {
+        return;
+    };
+    // GLib reaps the child when it exits, so it doesn't linger as a zombie
+    let argv: Vec<&OsStr> = command.iter().map(OsStr::new).collect();
+    if let Err(e) = gio::Subprocess::newv(&argv, gio::SubprocessFlags::NONE) {
+        eprintln!("Could not run {}: {}", program, e);
+    }
+}
+
+fn monitor_size(monitor: &gdk::Monitor) -> (i32, i32) {
+    let geometry = monitor.geometry();
+    (geometry.width(), geometry.height())
 }
 
-fn configure_layer_shell(window: &gtk::Window) {
-    gtk4_layer_shell::init_for_window(window);
-    gtk4_layer_shell::set_layer(window, gtk4_layer_shell::Layer::Overlay);
-    // Push other windows out of the way
-    gtk4_layer_shell::auto_exclusive_zone_enable(window);
-    let anchors = [
-        (gtk4_layer_shell::Edge::Left, true),
-        (gtk4_layer_shell::Edge::Right, true),
-        (gtk4_layer_shell::Edge::Top, false),
-        (gtk4_layer_shell::Edge::Bottom, true),
-    ];
-
-    for (anchor, state) in anchors {
-        gtk4_layer_shell::set_anchor(window, anchor, state);
+/// The layer shell edge the keyboard is attached to, and the one opposite it
+fn anchor_edges(anchor: Anchor) -> (gtk4_layer_shell::Edge, gtk4_layer_shell::Edge) {
+    match anchor {
+        Anchor::Top => (gtk4_layer_shell::Edge::Top, gtk4_layer_shell::Edge::Bottom),
+        Anchor::Bottom => (gtk4_layer_shell::Edge::Bottom, gtk4_layer_shell::Edge::Top),
     }
 }
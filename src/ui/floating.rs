@@ -0,0 +1,57 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// Where a floating keyboard sits on an output, and how big it is
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Placement {
+    /// Distance from the left edge of the output
+    pub x: i32,
+    /// Distance from the edge of the output the keyboard is anchored to
+    pub y: i32,
+    /// Size chosen by resizing the keyboard, overriding the configured size
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+}
+
+/// Saved placements, keyed by output connector name (e.g. "DP-1")
+type Placements = HashMap<String, Placement>;
+
+fn state_file() -> Option<PathBuf> {
+    dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .map(|path| path.join("dweeb/floating.toml"))
+}
+
+fn load_all() -> Placements {
+    state_file()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|contents| toml::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+/// The placement last used on `output`, if any
+pub fn load(output: &str) -> Option<Placement> {
+    load_all().remove(output)
+}
+
+/// Remember the placement for `output`, so the keyboard returns there next time
+pub fn save(output: &str, placement: Placement) {
+    let Some(path) = state_file() else {
+        return;
+    };
+    let mut placements = load_all();
+    placements.insert(output.to_string(), placement);
+
+    let result = toml::to_string(&placements)
+        .map_err(|e| e.to_string())
+        .and_then(|contents| {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+            }
+            std::fs::write(&path, contents).map_err(|e| e.to_string())
+        });
+    if let Err(e) = result {
+        eprintln!("Failed to save keyboard position: {}", e);
+    }
+}
@@ -1,30 +1,58 @@
-use gtk::prelude::*;
+use std::time::Duration;
+
+use gtk::{glib, prelude::*};
 use relm4::prelude::*;
 
-use crate::config::KeyConfig;
+use dweeb::{
+    config::KeyConfig,
+    engine::{self, KeySize, Layer, Swipe},
+};
 
-use super::{app::Layer, row::RowInput};
+use super::row::RowInput;
 
 pub struct Key {
     config: KeyConfig,
+    size: KeySize,
     layer: Layer,
-    classes: Vec<&'static str>,
+    pressed: bool,
+    /// Counts the times the key went down, so a hold timer from an earlier press is ignored
+    presses: u32,
+    /// Whether the key was held since it last went down, so letting go isn't a tap
+    held: bool,
+    /// Name the layout and page the key is on
+    page_classes: Vec<String>,
+    classes: Vec<String>,
 }
 
 #[derive(Debug)]
 pub enum KeyOutput {
-    KeyPress(String),
+    /// The key was pushed down, before it counts as a tap or hold
+    Down(KeyConfig),
+    KeyPress(KeyConfig),
+    Hold(KeyConfig),
+    /// The key was swiped far enough down to hide the keyboard
+    Dismiss,
 }
 
 #[derive(Debug, Clone)]
 pub enum KeyInput {
     Shift(Layer),
     KeyPress,
+    /// The key was pushed down (true) or let go (false)
+    Pressed(bool),
+    /// The key was held down for its hold time since going down for the nth time, triggering its
+    /// hold key or hint
+    Hold(u32),
+    /// The key was swiped down on, triggering its hint
+    HintPress,
+    /// The key was swiped down on for a long way
+    Dismiss,
 }
 
 #[relm4::factory(pub)]
 impl FactoryComponent for Key {
-    type Init = (KeyConfig, Layer);
+    /// (key, layer, size, page classes)
+    type Init = (KeyConfig, Layer, KeySize, Vec<String>);
     type Input = KeyInput;
     type Output = KeyOutput;
     type CommandOutput = ();
@@ -33,30 +61,83 @@ impl FactoryComponent for Key {
 
     view! {
         gtk::Button {
-            #[watch]
-            set_label?: match &self.config.icon {
-                Some(_) => None,
-                None => self.config.label.as_ref().or(Some(self.character())),
-            },
-            set_icon_name?: self.config.icon.as_ref(),
-            set_height_request: 80,
-            set_width_request: 100,
+            set_height_request: self.size.height,
+            set_width_request: self.size.width,
             set_hexpand: true,
             #[watch]
-            set_css_classes: &self.classes,
+            set_css_classes: &self.classes.iter().map(String::as_str).collect::<Vec<_>>(),
             connect_clicked => KeyInput::KeyPress,
+
+            // Track the pressed state for styling, holding, and page switching keys held down
+            add_controller = gtk::GestureClick {
+                set_propagation_phase: gtk::PropagationPhase::Capture,
+                connect_begin[sender] => move |_, _| sender.input(KeyInput::Pressed(true)),
+                connect_end[sender] => move |_, _| sender.input(KeyInput::Pressed(false)),
+            },
+
+            // Swiping down on it triggers the hint, and swiping further hides the keyboard
+            add_controller = gtk::GestureDrag {
+                set_propagation_phase: gtk::PropagationPhase::Capture,
+                connect_drag_update => move |gesture, x, y| {
+                    // Claiming the sequence cancels the button's own click, and the window's
+                    // swipe, so the key has to handle dismissing too
+                    if engine::swipe(x, y).is_some() {
+                        gesture.set_state(gtk::EventSequenceState::Claimed);
+                    }
+                },
+                // Wait until the swipe ends to tell a hint from a dismissal
+                connect_drag_end[sender] => move |_, x, y| match engine::swipe(x, y) {
+                    Some(Swipe::Hint) => sender.input(KeyInput::HintPress),
+                    Some(Swipe::Dismiss) => sender.input(KeyInput::Dismiss),
+                    None => {}
+                },
+            },
+
+            gtk::Overlay {
+                #[wrap(Some)]
+                set_child = &gtk::Box {
+                    set_halign: gtk::Align::Center,
+                    set_valign: gtk::Align::Center,
+
+                    gtk::Label {
+                        #[watch]
+                        set_label: self.label(),
+                        #[watch]
+                        set_visible: self.icon().is_none(),
+                    },
+                    gtk::Image {
+                        #[watch]
+                        set_icon_name: self.icon(),
+                        #[watch]
+                        set_visible: self.icon().is_some(),
+                    },
+                },
+
+                add_overlay = &gtk::Label {
+                    set_css_classes: &["hint"],
+                    set_label: self.config.hint.as_deref().unwrap_or_default(),
+                    set_visible: self.config.hint.is_some(),
+                    set_can_target: false,
+                    set_halign: gtk::Align::End,
+                    set_valign: gtk::Align::Start,
+                },
+            },
         }
     }
 
     fn init_model(
-        (config, layer): Self::Init,
+        (config, layer, size, page_classes): Self::Init,
         _index: &DynamicIndex,
         _sender: FactorySender<Self>,
     ) -> Self {
         let mut model = Self {
             config,
+            size,
             classes: Vec::new(),
-            // classes: config.classes.unwrap_or(Vec::new()),
+            pressed: false,
+            presses: 0,
+            held: false,
+            page_classes,
             layer,
         };
         model.update_classes();
@@ -65,7 +146,40 @@ impl FactoryComponent for Key {
 
     fn update(&mut self, msg: Self::Input, sender: FactorySender<Self>) {
         match msg {
-            KeyInput::KeyPress => sender.output(KeyOutput::KeyPress(self.character().clone())),
+            KeyInput::KeyPress => {
+                if !self.held {
+                    sender.output(KeyOutput::KeyPress(self.config.clone()))
+                }
+            }
+            KeyInput::Pressed(pressed) => {
+                self.pressed = pressed;
+                self.update_classes();
+                if pressed {
+                    self.presses += 1;
+                    self.held = false;
+                    // Holding the key down triggers its hold key, or else its hint
+                    if let Some(hold_time) = self.config.hold_time {
+                        let (sender, press) = (sender.clone(), self.presses);
+                        glib::timeout_add_local_once(Duration::from_millis(hold_time), move || {
+                            sender.input(KeyInput::Hold(press))
+                        });
+                    }
+                    sender.output(KeyOutput::Down(self.config.clone()));
+                }
+            }
+            KeyInput::Hold(press) => {
+                // Unless the key was let go since
+                if self.pressed && press == self.presses {
+                    self.held = true;
+                    sender.output(KeyOutput::Hold(self.config.clone()))
+                }
+            }
+            KeyInput::HintPress => {
+                if let Some(hint) = &self.config.hint {
+                    sender.output(KeyOutput::KeyPress(KeyConfig::new(hint)))
+                }
+            }
+            KeyInput::Dismiss => sender.output(KeyOutput::Dismiss),
             KeyInput::Shift(layer) => {
                 self.layer = layer;
                 self.update_classes()
@@ -75,23 +189,27 @@ impl FactoryComponent for Key {
 
     fn forward_to_parent(output: Self::Output) -> Option<RowInput> {
         match output {
+            KeyOutput::Down(key) => Some(RowInput::Down(key)),
             KeyOutput::KeyPress(key) => Some(RowInput::KeyPress(key)),
+            KeyOutput::Hold(key) => Some(RowInput::Hold(key)),
+            KeyOutput::Dismiss => Some(RowInput::Dismiss),
         }
     }
 }
 
 impl Key {
     fn update_classes(&mut self) {
-        self.classes.clear();
-        if self.config.char == "<shift>" && self.layer == Layer::Locked {
-            self.classes.push("suggested-action");
-        }
+        self.classes =
+            engine::key_classes(&self.config, &self.layer, self.pressed, &self.page_classes);
     }
 
-    fn character(&self) -> &String {
-        match (&self.layer, &self.config.upper) {
-            (Layer::Locked | Layer::Shifted, Some(c)) => &c,
-            _ => &self.config.char,
-        }
+    /// The text shown on the button
+    fn label(&self) -> &str {
+        engine::label(&self.config, &self.layer)
+    }
+
+    /// The icon shown instead of the text, if any
+    fn icon(&self) -> Option<&str> {
+        engine::icon(&self.config, &self.layer)
     }
 }
@@ -1,3 +1,6 @@
 pub mod app;
+mod floating;
 mod key;
+mod monitor;
 mod row;
+mod theme;
@@ -0,0 +1,110 @@
+use std::rc::Rc;
+
+use gtk::{
+    gdk::{self, Display},
+    glib::{clone, translate::ToGlibPtr, Cast},
+    prelude::*,
+};
+
+use dweeb::config::OutputSelection;
+
+/// The monitor the keyboard is (or will be) shown on
+pub fn current_monitor(window: &gtk::Window) -> Option<gdk::Monitor> {
+    let display = Display::default()?;
+    gtk4_layer_shell::monitor(window)
+        .or_else(|| {
+            window
+                .is_realized()
+                .then(|| display.monitor_at_surface(&window.surface()))
+                .flatten()
+        })
+        .or_else(|| {
+            display
+                .monitors()
+                .item(0)
+                .and_then(|monitor| monitor.downcast().ok())
+        })
+}
+
+/// The monitor with the given connector name (e.g. "DP-1"), if it's connected
+fn find_monitor(connector: &str) -> Option<gdk::Monitor> {
+    Display::default()?
+        .monitors()
+        .iter::<gdk::Monitor>()
+        .flatten()
+        .find(|monitor| monitor.connector().as_deref() == Some(connector))
+}
+
+/// Place the keyboard on the selected output, where `focused` is the output with the focused
+/// text field if it's known. Returns false if a named output isn't connected, in which case the
+/// compositor chooses the output.
+pub fn select_monitor(
+    window: &gtk::Window,
+    selection: &OutputSelection,
+    focused: Option<&str>,
+) -> bool {
+    let monitor = match selection {
+        OutputSelection::Named(name) => find_monitor(name),
+        OutputSelection::Focused => focused.and_then(find_monitor),
+    };
+    if gtk4_layer_shell::monitor(window) == monitor {
+        return true;
+    }
+    match &monitor {
+        Some(monitor) => gtk4_layer_shell::set_monitor(window, monitor),
+        // The bindings don't allow unsetting the monitor, though the library does
+        None => unsafe {
+            gtk4_layer_shell_sys::gtk_layer_set_monitor(
+                window.to_glib_none().0,
+                std::ptr::null_mut(),
+            )
+        },
+    }
+    monitor.is_some() || *selection == OutputSelection::Focused
+}
+
+/// Call `on_change` with the keyboard's monitor whenever it changes, or when its size, scale or
+/// rotation changes.
+pub fn watch_monitors(window: &gtk::Window, on_change: impl Fn(&gdk::Monitor) + 'static) {
+    let Some(display) = Display::default() else {
+        return;
+    };
+
+    let notify: Rc<dyn Fn()> = Rc::new(clone!(@strong window => move || {
+        if let Some(monitor) = current_monitor(&window) {
+            on_change(&monitor);
+        }
+    }));
+
+    let watch = {
+        let notify = notify.clone();
+        move |monitor: gdk::Monitor| {
+            let on_geometry = notify.clone();
+            monitor.connect_geometry_notify(move |_| on_geometry());
+            let on_scale = notify.clone();
+            monitor.connect_scale_factor_notify(move |_| on_scale());
+        }
+    };
+
+    let monitors = display.monitors();
+    for monitor in monitors.iter::<gdk::Monitor>().flatten() {
+        watch(monitor);
+    }
+    let on_items_changed = notify.clone();
+    monitors.connect_items_changed(move |monitors, position, _removed, added| {
+        for i in position..position + added {
+            if let Some(monitor) = monitors.item(i).and_then(|m| m.downcast().ok()) {
+                watch(monitor);
+            }
+        }
+        on_items_changed();
+    });
+
+    // The compositor may put the surface on a different monitor than the one we guessed
+    window.connect_realize(move |window| {
+        let on_enter = notify.clone();
+        window
+            .surface()
+            .connect_enter_monitor(move |_, _| on_enter());
+    });
+}
@@ -1,32 +1,57 @@
 use gtk::prelude::*;
 use relm4::{factory::FactoryVecDeque, prelude::*};
 
-use crate::config::KeyConfig;
+use dweeb::{
+    config::KeyConfig,
+    engine::{KeySize, Layer},
+};
 
 use super::{
-    app::{AppInput, Layer},
+    app::AppInput,
     key::{Key, KeyInput},
 };
 
 pub struct Row {
-    buttons: FactoryVecDeque<Key>,
+    /// All keys, or the left half when the keyboard is split
+    left: FactoryVecDeque<Key>,
+    /// The right half of the keys when the keyboard is split
+    right: FactoryVecDeque<Key>,
+    split: bool,
     spacing: i32,
+    layer: Layer,
+    /// Name the layout and page the row is on
+    page_classes: Vec<String>,
 }
 
 #[derive(Debug)]
 pub enum RowOutput {
-    KeyPress(String),
+    Down(KeyConfig),
+    KeyPress(KeyConfig),
+    Hold(KeyConfig),
+    Dismiss,
 }
 
 #[derive(Debug, Clone)]
 pub enum RowInput {
-    KeyPress(String),
+    Down(KeyConfig),
+    KeyPress(KeyConfig),
+    Hold(KeyConfig),
+    Dismiss,
     Shift(Layer),
 }
 
 #[relm4::factory(pub)]
 impl FactoryComponent for Row {
-    type Init = (Vec<(KeyConfig, Layer)>, i32);
+    /// (left keys, right keys, layer, spacing, key size, page classes). The right half is empty
+    /// unless the keyboard is split.
+    type Init = (
+        Vec<KeyConfig>,
+        Vec<KeyConfig>,
+        Layer,
+        i32,
+        KeySize,
+        Vec<String>,
+    );
     type Input = RowInput;
     type Output = RowOutput;
     type CommandOutput = ();
@@ -34,35 +59,79 @@ impl FactoryComponent for Row {
     type ParentWidget = gtk::Box;
 
     view! {
-        self.buttons.widget().clone() -> gtk::Box {
+        gtk::Box {
             set_orientation: gtk::Orientation::Horizontal,
             set_spacing: self.spacing,
+            #[watch]
+            set_css_classes: &["row"]
+                .into_iter()
+                .chain(self.layer.css_class())
+                .chain(self.page_classes.iter().map(String::as_str))
+                .collect::<Vec<_>>(),
+
+            // Split halves hug the screen edges, leaving a gap in the middle
+            self.left.widget().clone() -> gtk::Box {
+                set_orientation: gtk::Orientation::Horizontal,
+                set_spacing: self.spacing,
+                set_hexpand: true,
+                set_halign: if self.split { gtk::Align::Start } else { gtk::Align::Fill },
+            },
+            self.right.widget().clone() -> gtk::Box {
+                set_orientation: gtk::Orientation::Horizontal,
+                set_spacing: self.spacing,
+                set_hexpand: true,
+                set_halign: gtk::Align::End,
+                set_visible: self.split,
+            },
         }
     }
 
     fn init_model(
-        (keys, spacing): Self::Init,
+        (left_keys, right_keys, layer, spacing, key_size, page_classes): Self::Init,
         _index: &DynamicIndex,
         sender: FactorySender<Self>,
     ) -> Self {
-        let mut buttons = FactoryVecDeque::new(gtk::Box::default(), sender.input_sender());
-        for key in &keys {
-            buttons.guard().push_back(key.clone());
+        let mut left = FactoryVecDeque::new(gtk::Box::default(), sender.input_sender());
+        for key in left_keys {
+            left.guard()
+                .push_back((key, layer.clone(), key_size, page_classes.clone()));
+        }
+        let mut right = FactoryVecDeque::new(gtk::Box::default(), sender.input_sender());
+        for key in right_keys {
+            right
+                .guard()
+                .push_back((key, layer.clone(), key_size, page_classes.clone()));
+        }
+        Self {
+            split: !right.is_empty(),
+            left,
+            right,
+            spacing,
+            layer,
+            page_classes,
         }
-        let model = Self { buttons, spacing };
-        model
     }
 
     fn update(&mut self, msg: Self::Input, sender: FactorySender<Self>) {
         match msg {
+            RowInput::Down(key) => sender.output(RowOutput::Down(key)),
             RowInput::KeyPress(key) => sender.output(RowOutput::KeyPress(key)),
-            RowInput::Shift(shifted) => self.buttons.broadcast(KeyInput::Shift(shifted)),
+            RowInput::Hold(key) => sender.output(RowOutput::Hold(key)),
+            RowInput::Dismiss => sender.output(RowOutput::Dismiss),
+            RowInput::Shift(layer) => {
+                self.layer = layer.clone();
+                self.left.broadcast(KeyInput::Shift(layer.clone()));
+                self.right.broadcast(KeyInput::Shift(layer))
+            }
         }
     }
 
     fn forward_to_parent(output: Self::Output) -> Option<AppInput> {
         match output {
+            RowOutput::Down(key) => Some(AppInput::KeyDown(key)),
             RowOutput::KeyPress(key) => Some(AppInput::KeyPress(key)),
+            RowOutput::Hold(key) => Some(AppInput::KeyHold(key)),
+            RowOutput::Dismiss => Some(AppInput::Dismiss),
         }
     }
 }
@@ -0,0 +1,187 @@
+use std::{cell::Cell, path::PathBuf, rc::Rc};
+
+use dirs::config_dir;
+use gtk::{
+    gdk::Display,
+    gio,
+    glib::{self, ToVariant},
+    CssProvider, Settings,
+};
+
+/// Themes bundled with dweeb, used when no theme file with the same name is installed
+const BUILTIN_THEMES: [(&str, &str); 3] = [
+    ("light", include_str!("themes/light.css")),
+    ("dark", include_str!("themes/dark.css")),
+    ("high-contrast", include_str!("themes/high-contrast.css")),
+];
+
+/// Theme name which follows the system's light/dark preference
+const AUTO_THEME: &str = "auto";
+
+/// The desktop portal's settings, which hold the system's color scheme
+const PORTAL_NAME: &str = "org.freedesktop.portal.Desktop";
+const PORTAL_PATH: &str = "/org/freedesktop/portal/desktop";
+const PORTAL_SETTINGS: &str = "org.freedesktop.portal.Settings";
+const APPEARANCE_NAMESPACE: &str = "org.freedesktop.appearance";
+const COLOR_SCHEME_KEY: &str = "color-scheme";
+
+/// How long (in milliseconds) to wait for the portal at startup
+const PORTAL_TIMEOUT: i32 = 1000;
+
+/// Loads the base stylesheet, the configured theme and the user's style overrides, in
+/// increasing order of priority.
+#[derive(Clone)]
+pub struct Theme {
+    name: String,
+    light: String,
+    dark: String,
+    /// The portal's color scheme: whether it prefers dark, or None if it has no preference
+    portal_dark: Rc<Cell<Option<bool>>>,
+    provider: CssProvider,
+}
+impl Theme {
+    pub fn load(name: &str, light: &str, dark: &str) -> Self {
+        let display = Display::default().expect("Could not connect to a display.");
+
+        let base = CssProvider::new();
+        base.load_from_data(include_str!("style.css"));
+        gtk::style_context_add_provider_for_display(
+            &display,
+            &base,
+            gtk::STYLE_PROVIDER_PRIORITY_APPLICATION,
+        );
+
+        let theme = Theme {
+            name: name.to_string(),
+            light: light.to_string(),
+            dark: dark.to_string(),
+            portal_dark: Rc::new(Cell::new(None)),
+            provider: CssProvider::new(),
+        };
+        if theme.name == AUTO_THEME {
+            theme.follow_color_scheme();
+        }
+        theme.reload();
+        gtk::style_context_add_provider_for_display(
+            &display,
+            &theme.provider,
+            gtk::STYLE_PROVIDER_PRIORITY_APPLICATION + 1,
+        );
+
+        if let Some(path) = config_dir() {
+            if let Ok(user_css) = std::fs::read_to_string(path.join("dweeb/style.css")) {
+                let user = CssProvider::new();
+                user.load_from_data(&user_css);
+                gtk::style_context_add_provider_for_display(
+                    &display,
+                    &user,
+                    gtk::STYLE_PROVIDER_PRIORITY_USER,
+                );
+            }
+        }
+
+        theme
+    }
+
+    /// Switch between the light and dark variants whenever the system preference changes
+    fn follow_color_scheme(&self) {
+        // Desktops publish their color scheme through the portal, which GTK itself doesn't read
+        if let Ok(bus) = gio::bus_get_sync(gio::BusType::Session, gio::Cancellable::NONE) {
+            self.portal_dark.set(read_color_scheme(&bus));
+            let theme = self.clone();
+            bus.signal_subscribe(
+                Some(PORTAL_NAME),
+                Some(PORTAL_SETTINGS),
+                Some("SettingChanged"),
+                Some(PORTAL_PATH),
+                Some(APPEARANCE_NAMESPACE),
+                gio::DBusSignalFlags::NONE,
+                move |_, _, _, _, _, parameters| {
+                    if let Some((_, key, value)) =
+                        parameters.get::<(String, String, glib::Variant)>()
+                    {
+                        if key == COLOR_SCHEME_KEY {
+                            theme.portal_dark.set(is_dark(&value));
+                            theme.reload();
+                        }
+                    }
+                },
+            );
+        }
+        if let Some(settings) = Settings::default() {
+            let theme = self.clone();
+            settings.connect_gtk_application_prefer_dark_theme_notify(move |_| theme.reload());
+        }
+    }
+
+    /// Whether the system prefers a dark color scheme, asking GTK if the portal doesn't say
+    fn prefers_dark(&self) -> bool {
+        self.portal_dark.get().unwrap_or_else(|| {
+            Settings::default()
+                .is_some_and(|settings| settings.is_gtk_application_prefer_dark_theme())
+        })
+    }
+
+    /// (Re)load the CSS for the currently applicable theme
+    fn reload(&self) {
+        let name = match self.name.as_str() {
+            AUTO_THEME if self.prefers_dark() => &self.dark,
+            AUTO_THEME => &self.light,
+            name => name,
+        };
+        match theme_css(name) {
+            Some(css) => self.provider.load_from_data(&css),
+            None => eprintln!("Theme '{}' not found, using the default style", name),
+        }
+    }
+}
+
+/// The color scheme the portal currently reports
+fn read_color_scheme(bus: &gio::DBusConnection) -> Option<bool> {
+    let reply = bus
+        .call_sync(
+            Some(PORTAL_NAME),
+            PORTAL_PATH,
+            PORTAL_SETTINGS,
+            "Read",
+            Some(&(APPEARANCE_NAMESPACE, COLOR_SCHEME_KEY).to_variant()),
+            None,
+            gio::DBusCallFlags::NONE,
+            PORTAL_TIMEOUT,
+            gio::Cancellable::NONE,
+        )
+        .ok()?;
+    is_dark(&reply.child_value(0))
+}
+
+/// Whether a portal color scheme prefers dark (1) or light (2). 0 means no preference.
+fn is_dark(color_scheme: &glib::Variant) -> Option<bool> {
+    // Read wraps the value in more than one variant
+    let mut value = color_scheme.clone();
+    while let Some(inner) = value.as_variant() {
+        value = inner;
+    }
+    match value.get::<u32>()? {
+        1 => Some(true),
+        2 => Some(false),
+        _ => None,
+    }
+}
+
+/// Find a theme's CSS, searching the user's config directory, then the XDG data directories,
+/// then the themes bundled with dweeb.
+fn theme_css(name: &str) -> Option<String> {
+    let file_name = format!("{}.css", name);
+    config_dir()
+        .into_iter()
+        .chain(std::iter::once(glib::user_data_dir()))
+        .chain(glib::system_data_dirs())
+        .map(|dir: PathBuf| dir.join("dweeb/themes").join(&file_name))
+        .find_map(|path| std::fs::read_to_string(path).ok())
+        .or_else(|| {
+            BUILTIN_THEMES
+                .iter()
+                .find(|(builtin, _)| *builtin == name)
+                .map(|(_, css)| css.to_string())
+        })
+}
@@ -1,60 +1,570 @@
-use std::io::prelude::*;
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::prelude::*,
+    os::fd::AsFd,
+    path::PathBuf,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Instant,
+};
 use wayland_client::{
+    backend::WaylandError,
     protocol::{
+        wl_buffer::{self, WlBuffer},
+        wl_compositor::{self, WlCompositor},
+        wl_keyboard,
+        wl_output::{self, WlOutput},
+        wl_region::{self, WlRegion},
         wl_registry,
         wl_seat::{self, WlSeat},
+        wl_shm::{self, WlShm},
+        wl_shm_pool::{self, WlShmPool},
+        wl_surface::{self, WlSurface},
     },
-    Connection, Dispatch, EventQueue, QueueHandle,
+    ConnectError, Connection, Dispatch, DispatchError, EventQueue, Proxy, QueueHandle,
 };
-use wayland_protocols_misc::zwp_input_method_v2::client::{
-    zwp_input_method_manager_v2::{self, ZwpInputMethodManagerV2},
-    zwp_input_method_v2::{self, ZwpInputMethodV2},
+use wayland_protocols_misc::{
+    zwp_input_method_v2::client::{
+        zwp_input_method_manager_v2::{self, ZwpInputMethodManagerV2},
+        zwp_input_method_v2::{self, ZwpInputMethodV2},
+        zwp_input_popup_surface_v2::{self, ZwpInputPopupSurfaceV2},
+    },
+    zwp_virtual_keyboard_v1::client::{
+        zwp_virtual_keyboard_manager_v1::{self, ZwpVirtualKeyboardManagerV1},
+        zwp_virtual_keyboard_v1::{self, ZwpVirtualKeyboardV1},
+    },
 };
 
+use crate::config::{KeyCombo, Modifier};
+
+/// Messages from the Wayland thread to the GUI
+#[derive(Debug, Clone)]
+pub enum WaylandEvent {
+    /// A text field was focused. Text fields are identified by the activation they were
+    /// focused in.
+    Open(u32, TextField),
+    /// The focused text field's text or content type changed
+    TextField(TextField),
+    /// The focused text field is on the output with this name, e.g. "DP-1"
+    Output(String),
+    /// No text field is focused
+    Close,
+    Status(ConnectionStatus),
+    /// The Wayland thread has stopped, and dweeb can't work any more
+    Fatal(Error),
+}
+
+/// Messages from the GUI to the Wayland thread
+#[derive(Debug, Clone, PartialEq)]
+pub enum InputAction {
+    /// Insert text at the cursor, replacing the selection
+    Commit(String),
+    /// Delete characters before and after the cursor
+    DeleteSurrounding { before: u32, after: u32 },
+    /// Press keysyms with modifiers, one after another
+    Keys(Vec<KeyCombo>),
+}
+
+/// What kind of text a text field expects, as numbers from the text input protocol
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ContentType {
+    /// Flags like auto capitalization or hidden text
+    pub hint: u32,
+    /// E.g. digits, an email address or a password
+    pub purpose: u32,
+}
+
+impl ContentType {
+    /// Hint flags
+    pub const AUTO_CAPITALIZATION: u32 = 0x4;
+    pub const UPPERCASE: u32 = 0x10;
+
+    pub fn has_hint(&self, hint: u32) -> bool {
+        self.hint & hint != 0
+    }
+
+    /// Whether the text field takes numbers rather than words: digits, a number, a phone number
+    /// or a PIN
+    pub fn is_numeric(&self) -> bool {
+        matches!(self.purpose, 2 | 3 | 4 | 9)
+    }
+}
+
+/// What the compositor has told us about the focused text field
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TextField {
+    /// The name of the seat it's focused on
+    pub seat: Option<String>,
+    /// The text around the cursor and the cursor's byte offset into it
+    pub surrounding_text: Option<(String, u32)>,
+    pub content_type: Option<ContentType>,
+}
+
+/// Whether dweeb is able to type into text fields
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionStatus {
+    Connected,
+    /// Something went wrong, and we're trying to connect again
+    Retrying(Error),
+}
+impl std::fmt::Display for ConnectionStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionStatus::Connected => write!(f, "Connected"),
+            ConnectionStatus::Retrying(error) => write!(f, "{} Retrying...", error),
+        }
+    }
+}
+
+/// Things that can go wrong while talking to the compositor
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// The Wayland client library couldn't be loaded, or `WAYLAND_SOCKET` is invalid
+    Setup(String),
+    /// The compositor couldn't be reached, e.g. because it's restarting
+    NoCompositor,
+    /// The compositor doesn't support the input method protocol
+    NoInputMethodManager,
+    /// Another input method (e.g. another on-screen keyboard) is already running
+    Unavailable,
+    /// The configured seat doesn't exist, e.g. because of a typo
+    NoSuchSeat(String),
+    /// The connection to the compositor was lost
+    Disconnected(String),
+}
+impl Error {
+    /// Whether there's no point in trying to connect again
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, Error::Setup(_) | Error::NoInputMethodManager)
+    }
+}
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Setup(reason) => write!(f, "Could not set up a Wayland connection: {}.", reason),
+            Error::NoCompositor => write!(f, "Could not connect to the Wayland compositor."),
+            Error::NoInputMethodManager => write!(
+                f,
+                "The compositor doesn't support the input method protocol, so text input won't work."
+            ),
+            Error::Unavailable => write!(f, "Another input method is running, so dweeb can't type."),
+            Error::NoSuchSeat(name) => write!(f, "There's no seat named '{}' to type on.", name),
+            Error::Disconnected(reason) => {
+                write!(f, "Lost connection to the compositor ({}).", reason)
+            }
+        }
+    }
+}
+impl std::error::Error for Error {}
+impl From<ConnectError> for Error {
+    fn from(e: ConnectError) -> Self {
+        match e {
+            ConnectError::NoCompositor => Error::NoCompositor,
+            e => Error::Setup(e.to_string()),
+        }
+    }
+}
+impl From<DispatchError> for Error {
+    fn from(e: DispatchError) -> Self {
+        Error::Disconnected(e.to_string())
+    }
+}
+impl From<WaylandError> for Error {
+    fn from(e: WaylandError) -> Self {
+        Error::Disconnected(e.to_string())
+    }
+}
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Disconnected(e.to_string())
+    }
+}
+
+/// An input method for one seat. Every seat has its own focused text field.
 #[derive(Clone)]
-pub struct KeyboardWriter {
-    seat: Option<WlSeat>,
-    input_manager: Option<ZwpInputMethodManagerV2>,
-    pub input_method: Option<ZwpInputMethodV2>,
+struct SeatInput {
+    seat: WlSeat,
+    /// The seat's name, e.g. "seat0", once the compositor has sent it
+    name: Option<String>,
+    input_method: Option<ZwpInputMethodV2>,
     input_active: bool,
     input_serial: u32,
+    /// Whether an Activate event is waiting for the next Done event to take effect
+    pending_activate: bool,
+    /// When a text field on this seat was last focused, counted across all seats
+    activation: u32,
+    /// Set when the compositor refuses to let us act as an input method on this seat
+    unavailable: bool,
+    /// The text around the cursor and the cursor's byte offset into it, if the text field
+    /// shares them
+    surrounding_text: Option<(String, u32)>,
+    /// Surrounding text waiting for the next Done event to take effect
+    pending_surrounding_text: Option<(String, u32)>,
+    /// The kind of text the text field expects, if it says
+    content_type: Option<ContentType>,
+    pending_content_type: Option<ContentType>,
+    /// Created the first time keys are pressed on this seat
+    virtual_keyboard: Option<VirtualKeyboard>,
+    /// Shown next to the focused text field, to find out which output it's on
+    popup: Option<InputPopup>,
+    /// The output the popup is on
+    output: Option<WlOutput>,
+}
+impl SeatInput {
+    /// Convert a number of characters before and after the cursor into bytes, which is what
+    /// the protocol counts in
+    fn surrounding_bytes(&self, before: u32, after: u32) -> (u32, u32) {
+        // Without the text, or with a cursor that's not between characters, we have to hope
+        // it's ASCII
+        let Some((head, tail)) = self.surrounding_text.as_ref().and_then(|(text, cursor)| {
+            Some((text.get(..*cursor as usize)?, text.get(*cursor as usize..)?))
+        }) else {
+            return (before, after);
+        };
+        let bytes = |chars: &mut dyn Iterator<Item = char>, count| {
+            chars
+                .take(count as usize)
+                .map(char::len_utf8)
+                .sum::<usize>() as u32
+        };
+        (
+            bytes(&mut head.chars().rev(), before),
+            bytes(&mut tail.chars(), after),
+        )
+    }
+}
+
+/// A transparent pixel which the compositor shows next to the text cursor. The compositor tells
+/// us which output it entered, which is the output of the focused text field.
+#[derive(Clone)]
+struct InputPopup {
+    surface: WlSurface,
+    popup: ZwpInputPopupSurfaceV2,
+    buffer: WlBuffer,
+}
+impl InputPopup {
+    fn destroy(self) {
+        self.popup.destroy();
+        self.surface.destroy();
+        self.buffer.destroy();
+    }
+}
+
+/// A monitor, as the compositor knows it
+#[derive(Clone)]
+struct Output {
+    output: WlOutput,
+    /// The connector name, e.g. "DP-1", from version 4 of the protocol
+    name: Option<String>,
+}
+
+/// A keyboard for pressing keys that aren't text, like Return or ctrl+c
+#[derive(Clone)]
+struct VirtualKeyboard {
+    keyboard: ZwpVirtualKeyboardV1,
+    /// The keysyms in its keymap, where the keysym at index i has keycode i + 1
+    keysyms: Vec<String>,
+}
+impl VirtualKeyboard {
+    fn press(&mut self, combos: &[KeyCombo], time: u32) -> std::io::Result<()> {
+        let missing: Vec<String> = combos
+            .iter()
+            .map(|combo| combo.key.clone())
+            .filter(|key| !self.keysyms.contains(key))
+            .collect();
+        if !missing.is_empty() {
+            for key in missing {
+                if !self.keysyms.contains(&key) {
+                    self.keysyms.push(key);
+                }
+            }
+            let keymap = keymap(&self.keysyms);
+            let file = keymap_file(&keymap)?;
+            self.keyboard.keymap(
+                wl_keyboard::KeymapFormat::XkbV1.into(),
+                file.as_fd(),
+                keymap.len() as u32 + 1,
+            );
+        }
+
+        for combo in combos {
+            let Some(index) = self.keysyms.iter().position(|key| *key == combo.key) else {
+                continue;
+            };
+            let code = index as u32 + 1;
+            let modifiers = combo
+                .modifiers
+                .iter()
+                .fold(0, |mask, modifier| mask | modifier_mask(*modifier));
+            if modifiers != 0 {
+                self.keyboard.modifiers(modifiers, 0, 0, 0);
+            }
+            self.keyboard
+                .key(time, code, wl_keyboard::KeyState::Pressed.into());
+            self.keyboard
+                .key(time, code, wl_keyboard::KeyState::Released.into());
+            if modifiers != 0 {
+                self.keyboard.modifiers(0, 0, 0, 0);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The bit for a modifier in the real modifiers of an XKB keymap
+fn modifier_mask(modifier: Modifier) -> u32 {
+    match modifier {
+        Modifier::Shift => 1 << 0,
+        Modifier::Ctrl => 1 << 2,
+        Modifier::Alt => 1 << 3,
+        Modifier::Super => 1 << 6,
+    }
+}
+
+/// An XKB keymap with a key for each keysym
+fn keymap(keysyms: &[String]) -> String {
+    // XKB keycodes are evdev keycodes plus 8
+    let keycodes: String = (1..=keysyms.len())
+        .map(|code| format!("    <K{}> = {};\n", code, code + 8))
+        .collect();
+    let symbols: String = keysyms
+        .iter()
+        .enumerate()
+        .map(|(i, keysym)| format!("    key <K{}> {{ [ {} ] }};\n", i + 1, keysym))
+        .collect();
+    format!(
+        "xkb_keymap {{\n\
+         xkb_keycodes \"dweeb\" {{\n    minimum = 8;\n    maximum = {};\n{}}};\n\
+         xkb_types \"dweeb\" {{ include \"complete\" }};\n\
+         xkb_compat \"dweeb\" {{ include \"complete\" }};\n\
+         xkb_symbols \"dweeb\" {{\n{}}};\n\
+         }};\n",
+        keysyms.len() + 8,
+        keycodes,
+        symbols
+    )
+}
+
+/// A file holding `keymap`, null terminated, to share with the compositor
+fn keymap_file(keymap: &str) -> std::io::Result<File> {
+    shared_file("keymap", &[keymap.as_bytes(), &[0]].concat())
+}
+
+/// A file holding `contents`, to share with the compositor
+fn shared_file(kind: &str, contents: &[u8]) -> std::io::Result<File> {
+    static COUNT: AtomicU32 = AtomicU32::new(0);
+    let dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir);
+    let path = dir.join(format!(
+        "dweeb-{}-{}-{}",
+        kind,
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    // Only the file descriptor is needed
+    std::fs::remove_file(&path)?;
+    file.write_all(contents)?;
+    // The compositor shares the offset, in case it reads the file instead of mapping it
+    file.rewind()?;
+    Ok(file)
+}
+
+#[derive(Clone)]
+pub struct KeyboardWriter {
+    /// Seats by the name of their registry global
+    seats: HashMap<u32, SeatInput>,
+    input_manager: Option<ZwpInputMethodManagerV2>,
+    keyboard_manager: Option<ZwpVirtualKeyboardManagerV1>,
+    compositor: Option<WlCompositor>,
+    shm: Option<WlShm>,
+    /// Outputs by the name of their registry global
+    outputs: HashMap<u32, Output>,
+    queue: QueueHandle<KeyboardWriter>,
+    /// Key events are timestamped in milliseconds since this
+    start: Instant,
+    /// Only act as an input method for the seat with this name, rather than for all of them
+    seat_name: Option<String>,
+    /// Counts activations on every seat, so the most recently focused text field wins
+    activations: u32,
 }
 impl KeyboardWriter {
-    pub fn new(queue: &mut EventQueue<KeyboardWriter>) -> KeyboardWriter {
+    pub fn new(
+        queue: &mut EventQueue<KeyboardWriter>,
+        seat_name: Option<String>,
+    ) -> Result<KeyboardWriter, Error> {
         let mut state = KeyboardWriter {
-            seat: None,
+            seats: HashMap::new(),
             input_manager: None,
-            input_method: None,
-            // Default to true so it works even if text input detection doesn't
-            input_active: false,
-            input_serial: 0,
+            keyboard_manager: None,
+            compositor: None,
+            shm: None,
+            outputs: HashMap::new(),
+            queue: queue.handle(),
+            start: Instant::now(),
+            seat_name,
+            activations: 0,
         };
         // We have to roundtrip 3 times to activate the input_method handle, so that
         // Activate/Deactivate events start coming in.
-        queue.roundtrip(&mut state).unwrap();
-        queue.roundtrip(&mut state).unwrap();
-        queue.roundtrip(&mut state).unwrap();
-        state
+        for _ in 0..3 {
+            queue.roundtrip(&mut state)?;
+        }
+        if state.input_manager.is_none() {
+            return Err(Error::NoInputMethodManager);
+        }
+        // Every seat has sent its name by now
+        if let Some(name) = &state.seat_name {
+            if state.seats().next().is_none() {
+                return Err(Error::NoSuchSeat(name.clone()));
+            }
+        }
+        Ok(state)
+    }
+
+    /// The seats we're acting as an input method for
+    fn seats(&self) -> impl Iterator<Item = &SeatInput> {
+        self.seats.values().filter(|input| match &self.seat_name {
+            Some(name) => input.name.as_ref() == Some(name),
+            None => true,
+        })
+    }
+
+    /// The seat keys are typed into: the one whose text field was focused most recently
+    fn focused_seat(&self) -> Option<&SeatInput> {
+        self.seats()
+            .filter(|input| input.input_method.is_some())
+            .max_by_key(|input| (input.input_active, input.activation))
+    }
+
+    fn focused_seat_mut(&mut self) -> Option<&mut SeatInput> {
+        let id = self.focused_seat()?.seat.id();
+        self.seats.values_mut().find(|input| input.seat.id() == id)
+    }
+
+    /// What the compositor has told us about the text field keys are typed into
+    pub fn text_field(&self) -> TextField {
+        match self.focused_seat().filter(|input| input.input_active) {
+            Some(input) => TextField {
+                seat: input.name.clone(),
+                surrounding_text: input.surrounding_text.clone(),
+                content_type: input.content_type,
+            },
+            None => TextField::default(),
+        }
+    }
+
+    /// The name of the output the focused text field is on, once the compositor has shown the
+    /// popup next to it
+    pub fn focused_output(&self) -> Option<String> {
+        let output = self
+            .focused_seat()
+            .filter(|input| input.input_active)?
+            .output
+            .as_ref()?;
+        self.outputs
+            .values()
+            .find(|known| known.output == *output)?
+            .name
+            .clone()
+    }
+
+    /// Show a popup for `input_method`, or nothing if the compositor can't show one
+    fn input_popup(&self, input_method: &ZwpInputMethodV2, global: u32) -> Option<InputPopup> {
+        let (compositor, shm) = (self.compositor.as_ref()?, self.shm.as_ref()?);
+        let qh = &self.queue;
+        // A single transparent pixel
+        let file = match shared_file("popup", &[0; 4]) {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("Could not create the input popup: {}", e);
+                return None;
+            }
+        };
+        let pool = shm.create_pool(file.as_fd(), 4, qh, ());
+        let buffer = pool.create_buffer(0, 1, 1, 4, wl_shm::Format::Argb8888, qh, ());
+        pool.destroy();
+        let surface = compositor.create_surface(qh, global);
+        // Touches go through to whatever is underneath
+        let region = compositor.create_region(qh, ());
+        surface.set_input_region(Some(&region));
+        region.destroy();
+        let popup = input_method.get_input_popup_surface(&surface, qh, ());
+        surface.attach(Some(&buffer), 0, 0);
+        surface.commit();
+        Some(InputPopup {
+            surface,
+            popup,
+            buffer,
+        })
+    }
+
+    /// Whether another input method has taken over every seat, so text input won't work
+    pub fn is_unavailable(&self) -> bool {
+        let mut seats = self.seats().peekable();
+        seats.peek().is_some() && seats.all(|input| input.unavailable)
     }
 
+    /// Whether a text field is focused on any seat
     pub fn is_active(&self) -> bool {
-        self.input_active
+        self.seats().any(|input| input.input_active)
     }
 
-    pub fn send_key(&mut self, key: String) {
-        print!("{}", key);
-        std::io::stdout().flush().unwrap();
+    /// Identifies the latest activation, which changes every time a text field is focused
+    pub fn activation_serial(&self) -> u32 {
+        self.seats()
+            .map(|input| input.activation)
+            .max()
+            .unwrap_or(0)
+    }
 
-        if let Some(im) = self.input_method.as_mut() {
-            match key.as_str() {
-                // Special character handling
-                "<bksp>" => im.delete_surrounding_text(1, 0),
-                _ => im.commit_string(key),
+    pub fn send(&mut self, action: InputAction) {
+        let keyboard_manager = self.keyboard_manager.clone();
+        let queue = self.queue.clone();
+        let time = self.start.elapsed().as_millis() as u32;
+        let Some(input) = self.focused_seat_mut() else {
+            eprintln!("Warning: no custom input method found");
+            return;
+        };
+        let Some(im) = input.input_method.as_ref() else {
+            return;
+        };
+        match action {
+            InputAction::Commit(text) => {
+                print!("{}", text);
+                // Echoing keys is only for debugging, so it doesn't matter if it fails
+                let _ = std::io::stdout().flush();
+                im.commit_string(text);
+            }
+            InputAction::DeleteSurrounding { before, after } => {
+                let (before, after) = input.surrounding_bytes(before, after);
+                im.delete_surrounding_text(before, after);
+            }
+            InputAction::Keys(combos) => {
+                let Some(manager) = keyboard_manager else {
+                    eprintln!("Warning: the compositor doesn't support virtual keyboards");
+                    return;
+                };
+                let seat = &input.seat;
+                let keyboard = input
+                    .virtual_keyboard
+                    .get_or_insert_with(|| VirtualKeyboard {
+                        keyboard: manager.create_virtual_keyboard(seat, &queue, ()),
+                        keysyms: Vec::new(),
+                    });
+                if let Err(e) = keyboard.press(&combos, time) {
+                    eprintln!("Could not press keys: {}", e);
+                }
+                // Nothing was sent to the input method
+                return;
             }
-            im.commit(self.input_serial);
-        } else {
-            eprintln!("Warning: no custom input method found")
         }
+        im.commit(input.input_serial);
     }
 }
 
@@ -67,54 +577,245 @@ impl Dispatch<wl_registry::WlRegistry, ()> for KeyboardWriter {
         _: &Connection,
         qh: &QueueHandle<KeyboardWriter>,
     ) {
-        if let wl_registry::Event::Global {
-            name,
-            interface,
-            version,
-        } = event
-        {
-            // println!("[{}] {} v{}", name, interface, version);
-            if interface == "wl_seat" {
-                registry.bind::<WlSeat, (), KeyboardWriter>(name, version, qh, ());
-            }
-            if interface == "zwp_input_method_manager_v2" {
-                // println!("Creating input method");
-                state.input_manager = Some(
-                    registry.bind::<ZwpInputMethodManagerV2, (), KeyboardWriter>(
+        match event {
+            wl_registry::Event::Global {
+                name,
+                interface,
+                version,
+            } => {
+                // println!("[{}] {} v{}", name, interface, version);
+                if interface == "wl_seat" {
+                    let seat =
+                        registry.bind::<WlSeat, u32, KeyboardWriter>(name, version, qh, name);
+                    state.seats.insert(
                         name,
-                        version,
+                        SeatInput {
+                            seat,
+                            name: None,
+                            input_method: None,
+                            input_active: false,
+                            input_serial: 0,
+                            pending_activate: false,
+                            activation: 0,
+                            unavailable: false,
+                            surrounding_text: None,
+                            pending_surrounding_text: None,
+                            content_type: None,
+                            pending_content_type: None,
+                            virtual_keyboard: None,
+                            popup: None,
+                            output: None,
+                        },
+                    );
+                }
+                if interface == "zwp_input_method_manager_v2" {
+                    // println!("Creating input method");
+                    state.input_manager = Some(
+                        registry.bind::<ZwpInputMethodManagerV2, (), KeyboardWriter>(
+                            name,
+                            version,
+                            qh,
+                            (),
+                        ),
+                    );
+                }
+                if interface == "wl_compositor" {
+                    state.compositor = Some(registry.bind::<WlCompositor, (), KeyboardWriter>(
+                        name,
+                        version.min(4),
                         qh,
                         (),
-                    ),
-                );
+                    ));
+                }
+                if interface == "wl_shm" {
+                    state.shm = Some(registry.bind::<WlShm, (), KeyboardWriter>(name, 1, qh, ()));
+                }
+                if interface == "wl_output" {
+                    let output = registry.bind::<WlOutput, u32, KeyboardWriter>(
+                        name,
+                        version.min(4),
+                        qh,
+                        name,
+                    );
+                    state.outputs.insert(name, Output { output, name: None });
+                }
+                if interface == "zwp_virtual_keyboard_manager_v1" {
+                    state.keyboard_manager = Some(
+                        registry.bind::<ZwpVirtualKeyboardManagerV1, (), KeyboardWriter>(
+                            name,
+                            version,
+                            qh,
+                            (),
+                        ),
+                    );
+                }
             }
+            // A seat or output was unplugged
+            wl_registry::Event::GlobalRemove { name } => {
+                if let Some(output) = state.outputs.remove(&name) {
+                    if output.output.version() >= 3 {
+                        output.output.release();
+                    }
+                }
+                if let Some(input) = state.seats.remove(&name) {
+                    if let Some(popup) = input.popup {
+                        popup.destroy();
+                    }
+                    if let Some(im) = input.input_method {
+                        im.destroy();
+                    }
+                    if let Some(keyboard) = input.virtual_keyboard {
+                        keyboard.keyboard.destroy();
+                    }
+                    if input.seat.version() >= 5 {
+                        input.seat.release();
+                    }
+                }
+            }
+            _ => {}
         }
     }
 }
 
-impl Dispatch<WlSeat, ()> for KeyboardWriter {
+impl Dispatch<WlSeat, u32> for KeyboardWriter {
     fn event(
         state: &mut Self,
         seat: &WlSeat,
         event: wl_seat::Event,
-        _: &(),
+        global: &u32,
         _: &Connection,
         qh: &QueueHandle<KeyboardWriter>,
     ) {
-        if let wl_seat::Event::Name { .. } = event {
-            state.seat = Some(seat.to_owned());
+        if let wl_seat::Event::Name { name } = event {
             // eprintln!("Found seat: {}", name);
-            if let Some(im) = state.input_manager.as_ref() {
-                state.input_method = Some(im.get_input_method(seat, qh, ()));
-            } else {
-                eprintln!(
-                    "Unable to bind input method management protocol, text input won't work."
-                );
+            let Some(input) = state.seats.get_mut(global) else {
+                return;
+            };
+            input.name = Some(name);
+            // Without a manager, KeyboardWriter::new reports NoInputMethodManager
+            if let (None, Some(im)) = (&input.input_method, state.input_manager.as_ref()) {
+                let input_method = im.get_input_method(seat, qh, *global);
+                let popup = state.input_popup(&input_method, *global);
+                if let Some(input) = state.seats.get_mut(global) {
+                    input.input_method = Some(input_method);
+                    input.popup = popup;
+                }
+            }
+        }
+    }
+}
+
+impl Dispatch<WlOutput, u32> for KeyboardWriter {
+    fn event(
+        state: &mut Self,
+        _: &WlOutput,
+        event: wl_output::Event,
+        global: &u32,
+        _: &Connection,
+        _: &QueueHandle<KeyboardWriter>,
+    ) {
+        if let (wl_output::Event::Name { name }, Some(output)) =
+            (event, state.outputs.get_mut(global))
+        {
+            output.name = Some(name);
+        }
+    }
+}
+
+impl Dispatch<WlSurface, u32> for KeyboardWriter {
+    fn event(
+        state: &mut Self,
+        _: &WlSurface,
+        event: wl_surface::Event,
+        global: &u32,
+        _: &Connection,
+        _: &QueueHandle<KeyboardWriter>,
+    ) {
+        let Some(input) = state.seats.get_mut(global) else {
+            return;
+        };
+        match event {
+            wl_surface::Event::Enter { output } => input.output = Some(output),
+            wl_surface::Event::Leave { output } if input.output.as_ref() == Some(&output) => {
+                input.output = None
             }
+            _ => {}
         }
     }
 }
 
+impl Dispatch<WlCompositor, ()> for KeyboardWriter {
+    fn event(
+        _: &mut Self,
+        _: &WlCompositor,
+        _: wl_compositor::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<KeyboardWriter>,
+    ) {
+    }
+}
+
+impl Dispatch<WlRegion, ()> for KeyboardWriter {
+    fn event(
+        _: &mut Self,
+        _: &WlRegion,
+        _: wl_region::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<KeyboardWriter>,
+    ) {
+    }
+}
+
+impl Dispatch<WlShm, ()> for KeyboardWriter {
+    fn event(
+        _: &mut Self,
+        _: &WlShm,
+        _: wl_shm::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<KeyboardWriter>,
+    ) {
+    }
+}
+
+impl Dispatch<WlShmPool, ()> for KeyboardWriter {
+    fn event(
+        _: &mut Self,
+        _: &WlShmPool,
+        _: wl_shm_pool::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<KeyboardWriter>,
+    ) {
+    }
+}
+
+impl Dispatch<WlBuffer, ()> for KeyboardWriter {
+    fn event(
+        _: &mut Self,
+        _: &WlBuffer,
+        _: wl_buffer::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<KeyboardWriter>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwpInputPopupSurfaceV2, ()> for KeyboardWriter {
+    fn event(
+        _: &mut Self,
+        _: &ZwpInputPopupSurfaceV2,
+        _: zwp_input_popup_surface_v2::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<KeyboardWriter>,
+    ) {
+    }
+}
+
 impl Dispatch<ZwpInputMethodManagerV2, ()> for KeyboardWriter {
     fn event(
         _state: &mut Self,
@@ -127,30 +828,406 @@ impl Dispatch<ZwpInputMethodManagerV2, ()> for KeyboardWriter {
     }
 }
 
-impl Dispatch<ZwpInputMethodV2, ()> for KeyboardWriter {
+impl Dispatch<ZwpVirtualKeyboardManagerV1, ()> for KeyboardWriter {
+    fn event(
+        _: &mut Self,
+        _: &ZwpVirtualKeyboardManagerV1,
+        _: zwp_virtual_keyboard_manager_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<KeyboardWriter>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwpVirtualKeyboardV1, ()> for KeyboardWriter {
+    fn event(
+        _: &mut Self,
+        _: &ZwpVirtualKeyboardV1,
+        _: zwp_virtual_keyboard_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<KeyboardWriter>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwpInputMethodV2, u32> for KeyboardWriter {
     fn event(
         state: &mut Self,
         _: &ZwpInputMethodV2,
         event: zwp_input_method_v2::Event,
-        _: &(),
+        global: &u32,
         _: &Connection,
         _: &QueueHandle<KeyboardWriter>,
     ) {
+        let Some(input) = state.seats.get_mut(global) else {
+            return;
+        };
         // println!("Received input method event! {}", event.opcode());
         match event {
             zwp_input_method_v2::Event::Activate => {
                 // eprintln!("Input method activated!");
-                state.input_active = true;
+                input.input_active = true;
+                input.pending_activate = true;
+                // Activating resets everything we know about the text field
+                input.pending_surrounding_text = None;
+                input.pending_content_type = None;
             }
             zwp_input_method_v2::Event::Deactivate => {
                 // eprintln!("Input method deactivated!");
-                state.input_active = false;
+                input.input_active = false;
+                input.pending_surrounding_text = None;
+                input.pending_content_type = None;
+            }
+            zwp_input_method_v2::Event::SurroundingText { text, cursor, .. } => {
+                input.pending_surrounding_text = Some((text, cursor));
+            }
+            zwp_input_method_v2::Event::ContentType { hint, purpose } => {
+                input.pending_content_type = Some(ContentType {
+                    hint: hint.into(),
+                    purpose: purpose.into(),
+                });
             }
             zwp_input_method_v2::Event::Done => {
                 // eprintln!("Received done event");
-                state.input_serial += 1;
+                input.input_serial += 1;
+                // What we know about the text field lasts until it's changed or unfocused
+                if input.pending_activate || !input.input_active {
+                    input.surrounding_text = None;
+                    input.content_type = None;
+                }
+                if let Some(text) = input.pending_surrounding_text.take() {
+                    input.surrounding_text = Some(text);
+                }
+                if let Some(content_type) = input.pending_content_type.take() {
+                    input.content_type = Some(content_type);
+                }
+                if input.pending_activate {
+                    input.pending_activate = false;
+                    state.activations += 1;
+                    input.activation = state.activations;
+                }
+            }
+            zwp_input_method_v2::Event::Unavailable => {
+                // The input method object is useless from now on
+                if let Some(popup) = input.popup.take() {
+                    popup.destroy();
+                }
+                if let Some(im) = input.input_method.take() {
+                    im.destroy();
+                }
+                input.input_active = false;
+                input.unavailable = true;
             }
             _ => {}
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_compositor::{ContentHint, ContentPurpose, MockCompositor, Request};
+
+    fn connect(
+        conn: &Connection,
+        seat: Option<&str>,
+    ) -> (EventQueue<KeyboardWriter>, KeyboardWriter) {
+        let mut queue = conn.new_event_queue();
+        conn.display().get_registry(&queue.handle(), ());
+        let writer = KeyboardWriter::new(&mut queue, seat.map(str::to_string)).unwrap();
+        (queue, writer)
+    }
+
+    fn commit(seat: &str, text: &str) -> Request {
+        Request::CommitString {
+            seat: seat.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn activation() {
+        let (compositor, conn) = MockCompositor::start(&["seat0"]);
+        let (mut queue, mut writer) = connect(&conn, None);
+        assert!(!writer.is_active());
+
+        compositor.activate("seat0");
+        queue.roundtrip(&mut writer).unwrap();
+        assert!(writer.is_active());
+        let first = writer.activation_serial();
+
+        compositor.deactivate("seat0");
+        queue.roundtrip(&mut writer).unwrap();
+        assert!(!writer.is_active());
+        assert_eq!(writer.activation_serial(), first);
+
+        compositor.activate("seat0");
+        queue.roundtrip(&mut writer).unwrap();
+        assert!(writer.is_active());
+        assert_ne!(writer.activation_serial(), first);
+    }
+
+    #[test]
+    fn commit_and_delete() {
+        let (compositor, conn) = MockCompositor::start(&["seat0"]);
+        let (mut queue, mut writer) = connect(&conn, None);
+        compositor.activate("seat0");
+        queue.roundtrip(&mut writer).unwrap();
+
+        writer.send(InputAction::Commit("a".to_string()));
+        writer.send(InputAction::DeleteSurrounding {
+            before: 1,
+            after: 0,
+        });
+        conn.flush().unwrap();
+
+        let seat = "seat0".to_string();
+        assert_eq!(
+            compositor.wait_for_requests(4),
+            vec![
+                commit("seat0", "a"),
+                Request::Commit {
+                    seat: seat.clone(),
+                    serial: 1
+                },
+                Request::DeleteSurroundingText {
+                    seat: seat.clone(),
+                    before: 1,
+                    after: 0
+                },
+                Request::Commit { seat, serial: 1 },
+            ]
+        );
+    }
+
+    #[test]
+    fn text_field() {
+        let (compositor, conn) = MockCompositor::start(&["seat0"]);
+        let (mut queue, mut writer) = connect(&conn, None);
+        assert_eq!(writer.text_field(), TextField::default());
+
+        compositor.activate_with_text("seat0", "12");
+        compositor.content_type(
+            "seat0",
+            ContentHint::AutoCapitalization,
+            ContentPurpose::Pin,
+        );
+        queue.roundtrip(&mut writer).unwrap();
+        let field = writer.text_field();
+        assert_eq!(field.seat.as_deref(), Some("seat0"));
+        assert_eq!(field.surrounding_text, Some(("12".to_string(), 2)));
+        let content_type = field.content_type.unwrap();
+        assert!(content_type.is_numeric());
+        assert!(content_type.has_hint(ContentType::AUTO_CAPITALIZATION));
+
+        // Focusing another text field forgets the content type
+        compositor.activate("seat0");
+        queue.roundtrip(&mut writer).unwrap();
+        assert_eq!(writer.text_field().content_type, None);
+    }
+
+    #[test]
+    fn focused_output() {
+        let (compositor, conn) = MockCompositor::start(&["seat0"]);
+        let (mut queue, mut writer) = connect(&conn, None);
+        compositor.activate("seat0");
+        queue.roundtrip(&mut writer).unwrap();
+        // The compositor hasn't shown the popup yet
+        assert_eq!(writer.focused_output(), None);
+
+        compositor.show_popup("seat0", "HDMI-A-1");
+        queue.roundtrip(&mut writer).unwrap();
+        assert_eq!(writer.focused_output().as_deref(), Some("HDMI-A-1"));
+
+        compositor.deactivate("seat0");
+        queue.roundtrip(&mut writer).unwrap();
+        assert_eq!(writer.focused_output(), None);
+    }
+
+    #[test]
+    fn press_keys() {
+        let (compositor, conn) = MockCompositor::start(&["seat0"]);
+        let (mut queue, mut writer) = connect(&conn, None);
+        compositor.activate("seat0");
+        queue.roundtrip(&mut writer).unwrap();
+
+        let combos = |combos: &[&str]| {
+            combos
+                .iter()
+                .map(|combo| KeyCombo::try_from(combo.to_string()).unwrap())
+                .collect()
+        };
+        writer.send(InputAction::Keys(combos(&["ctrl+c", "Return"])));
+        // Keys that are in the keymap already don't need a new one
+        writer.send(InputAction::Keys(combos(&["c"])));
+        conn.flush().unwrap();
+
+        let requests = compositor.wait_for_requests(9);
+        let Request::Keymap { keymap, .. } = &requests[0] else {
+            panic!("Expected a keymap, got {:?}", requests[0]);
+        };
+        assert!(keymap.contains("key <K1> { [ c ] };"), "{}", keymap);
+        assert!(keymap.contains("key <K2> { [ Return ] };"), "{}", keymap);
+
+        let seat = "seat0".to_string();
+        let key = |key, pressed| Request::Key {
+            seat: seat.clone(),
+            key,
+            pressed,
+        };
+        let modifiers = |depressed| Request::Modifiers {
+            seat: seat.clone(),
+            depressed,
+        };
+        assert_eq!(
+            requests[1..],
+            vec![
+                modifiers(4),
+                key(1, true),
+                key(1, false),
+                modifiers(0),
+                key(2, true),
+                key(2, false),
+                key(1, true),
+                key(1, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn delete_counts_characters() {
+        let (compositor, conn) = MockCompositor::start(&["seat0"]);
+        let (mut queue, mut writer) = connect(&conn, None);
+        compositor.activate("seat0");
+        // The cursor is after "é", which is two bytes
+        compositor.surrounding_text("seat0", "café!", 5);
+        queue.roundtrip(&mut writer).unwrap();
+
+        writer.send(InputAction::DeleteSurrounding {
+            before: 1,
+            after: 1,
+        });
+        conn.flush().unwrap();
+        assert_eq!(
+            compositor.wait_for_requests(1)[0],
+            Request::DeleteSurroundingText {
+                seat: "seat0".to_string(),
+                before: 2,
+                after: 1
+            }
+        );
+
+        // A cursor in the middle of "é" doesn't crash, but falls back to counting bytes
+        compositor.surrounding_text("seat0", "café!", 4);
+        queue.roundtrip(&mut writer).unwrap();
+        writer.send(InputAction::DeleteSurrounding {
+            before: 1,
+            after: 0,
+        });
+        conn.flush().unwrap();
+        assert_eq!(
+            compositor.wait_for_requests(3)[2],
+            Request::DeleteSurroundingText {
+                seat: "seat0".to_string(),
+                before: 1,
+                after: 0
+            }
+        );
+    }
+
+    #[test]
+    fn types_on_most_recently_focused_seat() {
+        let (compositor, conn) = MockCompositor::start(&["seat0", "seat1"]);
+        let (mut queue, mut writer) = connect(&conn, None);
+
+        compositor.activate("seat0");
+        compositor.activate("seat1");
+        queue.roundtrip(&mut writer).unwrap();
+        writer.send(InputAction::Commit("1".to_string()));
+
+        compositor.deactivate("seat1");
+        queue.roundtrip(&mut writer).unwrap();
+        assert!(writer.is_active());
+        writer.send(InputAction::Commit("0".to_string()));
+        conn.flush().unwrap();
+
+        let commits: Vec<Request> = compositor
+            .wait_for_requests(4)
+            .into_iter()
+            .filter(|request| matches!(request, Request::CommitString { .. }))
+            .collect();
+        assert_eq!(commits, vec![commit("seat1", "1"), commit("seat0", "0")]);
+    }
+
+    #[test]
+    fn configured_seat() {
+        let (compositor, conn) = MockCompositor::start(&["seat0", "seat1"]);
+        let (mut queue, mut writer) = connect(&conn, Some("seat1"));
+
+        compositor.activate("seat0");
+        queue.roundtrip(&mut writer).unwrap();
+        assert!(!writer.is_active());
+
+        compositor.activate("seat1");
+        queue.roundtrip(&mut writer).unwrap();
+        assert!(writer.is_active());
+        writer.send(InputAction::Commit("x".to_string()));
+        conn.flush().unwrap();
+        assert_eq!(compositor.wait_for_requests(1)[0], commit("seat1", "x"));
+    }
+
+    #[test]
+    fn missing_seat() {
+        let (_compositor, conn) = MockCompositor::start(&["seat0"]);
+        let mut queue = conn.new_event_queue();
+        conn.display().get_registry(&queue.handle(), ());
+        assert_eq!(
+            KeyboardWriter::new(&mut queue, Some("seat1".to_string())).err(),
+            Some(Error::NoSuchSeat("seat1".to_string()))
+        );
+    }
+
+    #[test]
+    fn seat_hotplug() {
+        let (compositor, conn) = MockCompositor::start(&["seat0"]);
+        let (mut queue, mut writer) = connect(&conn, None);
+
+        compositor.add_seat("seat1");
+        queue.roundtrip(&mut writer).unwrap();
+        queue.roundtrip(&mut writer).unwrap();
+        compositor.activate("seat1");
+        queue.roundtrip(&mut writer).unwrap();
+        assert!(writer.is_active());
+
+        compositor.remove_seat("seat1");
+        queue.roundtrip(&mut writer).unwrap();
+        assert!(!writer.is_active());
+    }
+
+    #[test]
+    fn unavailable() {
+        let (compositor, conn) = MockCompositor::start(&["seat0", "seat1"]);
+        let (mut queue, mut writer) = connect(&conn, None);
+
+        compositor.unavailable("seat0");
+        queue.roundtrip(&mut writer).unwrap();
+        assert!(!writer.is_unavailable());
+
+        compositor.unavailable("seat1");
+        queue.roundtrip(&mut writer).unwrap();
+        assert!(writer.is_unavailable());
+    }
+
+    #[test]
+    fn no_input_method_manager() {
+        let (_compositor, conn) = MockCompositor::start_without_input_method(&["seat0"]);
+        let mut queue = conn.new_event_queue();
+        conn.display().get_registry(&queue.handle(), ());
+        assert_eq!(
+            KeyboardWriter::new(&mut queue, None).err(),
+            Some(Error::NoInputMethodManager)
+        );
+    }
+}